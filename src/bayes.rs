@@ -0,0 +1,125 @@
+use crate::normalize;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Naive Bayes spam model over word unigrams and bigrams, trained from moderation history
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct NaiveBayes {
+    spam_docs: u64,
+    ham_docs: u64,
+    spam_counts: HashMap<String, u64>,
+    ham_counts: HashMap<String, u64>,
+    spam_tokens: u64,
+    ham_tokens: u64,
+    /// Distinct tokens seen in either class. Models saved without it count it again on the
+    /// next retraining.
+    #[serde(default)]
+    vocabulary: u64,
+}
+
+/// Words and word pairs of the skeleton of a text
+fn tokens(text: &str) -> Vec<String> {
+    let skeleton = normalize::skeleton(text);
+    let words = skeleton
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>();
+    words
+        .iter()
+        .map(|w| w.to_string())
+        .chain(words.windows(2).map(|pair| pair.join(" ")))
+        .collect()
+}
+
+impl NaiveBayes {
+    /// Train on `(text, is_spam)` samples
+    pub fn train<'a>(samples: impl IntoIterator<Item = (&'a str, bool)>) -> Self {
+        let mut model = Self::default();
+        for (text, spam) in samples {
+            let (docs, counts, total) = if spam {
+                (
+                    &mut model.spam_docs,
+                    &mut model.spam_counts,
+                    &mut model.spam_tokens,
+                )
+            } else {
+                (
+                    &mut model.ham_docs,
+                    &mut model.ham_counts,
+                    &mut model.ham_tokens,
+                )
+            };
+            *docs += 1;
+            for token in tokens(text) {
+                *counts.entry(token).or_default() += 1;
+                *total += 1;
+            }
+        }
+        model.vocabulary = model
+            .spam_counts
+            .keys()
+            .chain(model.ham_counts.keys())
+            .collect::<HashSet<_>>()
+            .len() as u64;
+        model
+    }
+
+    /// Number of spam and ham samples the model was trained on
+    pub fn samples(&self) -> (u64, u64) {
+        (self.spam_docs, self.ham_docs)
+    }
+
+    /// Probability of a text being spam, `None` if the model has not seen both classes or
+    /// none of the words of the text
+    pub fn spam_probability(&self, text: &str) -> Option<f64> {
+        if self.spam_docs == 0 || self.ham_docs == 0 {
+            return None;
+        }
+
+        let vocabulary = self.vocabulary as f64;
+        let log_likelihood = |count: Option<&u64>, total: u64| {
+            ((count.copied().unwrap_or(0) as f64 + 1.0) / (total as f64 + vocabulary)).ln()
+        };
+
+        let docs = (self.spam_docs + self.ham_docs) as f64;
+        let mut log_spam = (self.spam_docs as f64 / docs).ln();
+        let mut log_ham = (self.ham_docs as f64 / docs).ln();
+        let mut known = false;
+        for token in tokens(text) {
+            let spam_count = self.spam_counts.get(&token);
+            let ham_count = self.ham_counts.get(&token);
+            // Words never seen in training say nothing
+            if spam_count.is_none() && ham_count.is_none() {
+                continue;
+            }
+            known = true;
+            log_spam += log_likelihood(spam_count, self.spam_tokens);
+            log_ham += log_likelihood(ham_count, self.ham_tokens);
+        }
+
+        known.then(|| 1.0 / (1.0 + (log_ham - log_spam).exp()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spam_probability() {
+        let model = NaiveBayes::train([
+            ("Free crypto airdrop, claim your tokens now", true),
+            ("Earn 500$ daily from home, DM me", true),
+            ("Claim free USDT airdrop today", true),
+            ("Does anyone know how to fix this build error?", false),
+            ("Thanks, the new release works fine", false),
+            ("See you all at the meetup tomorrow", false),
+        ]);
+        assert_eq!(model.samples(), (3, 3));
+
+        assert!(model.spam_probability("CLAIM frее airdrop").unwrap() > 0.9);
+        assert!(model.spam_probability("the build works fine now").unwrap() < 0.1);
+        assert_eq!(model.spam_probability("zzz"), None);
+        assert_eq!(NaiveBayes::default().spam_probability("airdrop"), None);
+    }
+}
@@ -1,11 +1,20 @@
-use crate::config::Settings;
-use crate::detect::MsgType;
+use crate::budget::BudgetExhausted;
+use crate::cache::VerdictCache;
+use crate::config::{FailPolicy, Settings, ShadowMode};
+use crate::detect::{self, MsgType, SpamCheckRequest, SpamCheckResult, SpamClassifier};
+use crate::embedding::SemanticIndex;
+use crate::links::{Links, normalize_domain};
+use crate::post::Action;
+use crate::rules::Rules;
+use crate::sender::Sender;
 use crate::state::AppState;
-use crate::{detect::Agent, post};
+use crate::usage::DailyUsage;
+use crate::{media, post};
 use std::sync::Arc;
 use teloxide::prelude::*;
-use teloxide::types::ReplyParameters;
+use teloxide::types::{InputFile, ReplyParameters};
 use teloxide::utils::command::BotCommands;
+use tokio::time::{self, Duration};
 
 #[derive(BotCommands, Clone, Debug)]
 #[command(
@@ -14,7 +23,8 @@ use teloxide::utils::command::BotCommands;
     parse_with = "split"
 )]
 // NOTE: Explicitly set zero argument tuple together with parse_with = "split" to prevent user from
-// spamming using command invocation.
+// spamming using command invocation. Commands taking an argument must be listed in
+// `Command::takes_argument`, so that they only run for admins.
 enum Command {
     #[command(description = "Start the bot")]
     Start(),
@@ -26,29 +36,119 @@ enum Command {
     Reset(),
     #[command(description = "Clear context")]
     ClearContext(),
+    #[command(
+        description = "Set what happens when the spam check fails: open, hold, delete or recheck (admin only)"
+    )]
+    FailPolicy(String),
+    #[command(description = "Show the moderation policy of this chat")]
+    Policy(),
+    #[command(
+        description = "Set the moderation policy of this chat given to the classifier (admin only)",
+        parse_with = "default"
+    )]
+    SetPolicy(String),
+    #[command(description = "Remove the moderation policy of this chat (admin only)")]
+    ClearPolicy(),
+    #[command(description = "Show the domain lists of this chat")]
+    Domains(),
+    #[command(description = "Allow links to a domain in this chat (admin only)")]
+    AllowDomain(String),
+    #[command(description = "Treat links to a domain as phishing in this chat (admin only)")]
+    DenyDomain(String),
+    #[command(description = "Remove a domain from the lists of this chat (admin only)")]
+    RemoveDomain(String),
+    #[command(
+        description = "Show the shadow mode of this chat, or set it to off, log or report (admin only)",
+        parse_with = "default"
+    )]
+    Shadow(String),
+    #[command(description = "Show classifier token usage of this chat (admin only)")]
+    Usage(),
+    #[command(
+        description = "Export classifier token usage as CSV, of all chats in the admin chat (admin only)"
+    )]
+    ExportUsage(),
 }
 
+impl Command {
+    /// Commands carrying text of the sender. Only admins may run them, for anyone else the
+    /// message goes through the spam check like any other.
+    fn takes_argument(&self) -> bool {
+        matches!(
+            self,
+            Self::FailPolicy(_)
+                | Self::SetPolicy(_)
+                | Self::AllowDomain(_)
+                | Self::DenyDomain(_)
+                | Self::RemoveDomain(_)
+        ) || matches!(self, Self::Shadow(mode) if !mode.trim().is_empty())
+    }
+}
+
+/// Whether a command message is handled as a command rather than checked for spam
+async fn may_run(bot: Bot, msg: Message, cmd: Command) -> bool {
+    if !cmd.takes_argument() {
+        return true;
+    }
+    match &msg.from {
+        Some(user) => is_admin(&bot, msg.chat.id, user.id).await.unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Longest accepted chat policy, in characters
+const MAX_POLICY_LEN: usize = 2000;
+
+/// Number of days shown by `/usage`
+const USAGE_REPORT_DAYS: i64 = 7;
+
+/// Confidence from which a deleted scam or phishing message is fingerprinted without waiting
+/// for an admin to confirm it with a kick
+const FINGERPRINT_CONFIDENCE: f32 = 0.95;
+
 pub async fn run_bot(
     bot: Bot,
-    agent: Arc<Agent>,
+    classifier: Arc<dyn SpamClassifier>,
+    rules: Arc<Rules>,
+    cache: Arc<VerdictCache>,
+    semantic: Arc<SemanticIndex>,
     state: Arc<AppState>,
     settings: Arc<Settings>,
 ) -> anyhow::Result<()> {
     let command_handler = Update::filter_message()
         .filter_command::<Command>()
+        .filter_async(may_run)
         .endpoint(handle_command);
 
     let message_handler = Update::filter_message().endpoint(handle_spam_check);
 
+    // Spammers may post something harmless and edit it into spam later
+    let edited_message_handler = Update::filter_edited_message().endpoint(handle_spam_check);
+
     let callback_handler = Update::filter_callback_query().endpoint(handle_callback_query);
 
+    tokio::spawn(recheck_loop(
+        bot.clone(),
+        classifier.clone(),
+        rules.clone(),
+        cache.clone(),
+        semantic.clone(),
+        state.clone(),
+        settings.clone(),
+    ));
+
+    tokio::spawn(retrain_loop(state.clone(), settings.clone()));
+
     let handler = dptree::entry()
         .branch(command_handler)
         .branch(callback_handler)
-        .branch(message_handler);
+        .branch(message_handler)
+        .branch(edited_message_handler);
 
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![agent, state, settings])
+        .dependencies(dptree::deps![
+            classifier, rules, cache, semantic, state, settings
+        ])
         .enable_ctrlc_handler()
         .build()
         .dispatch()
@@ -61,6 +161,7 @@ async fn handle_command(
     bot: Bot,
     msg: Message,
     cmd: Command,
+    classifier: Arc<dyn SpamClassifier>,
     state: Arc<AppState>,
     settings: Arc<Settings>,
 ) -> ResponseResult<()> {
@@ -79,9 +180,17 @@ async fn handle_command(
         }
         Command::Stats() => {
             let count = state.get_count(chat_id, user_id);
-            bot.send_message(chat_id, format!("Your message count: {}", count))
-                .reply_parameters(ReplyParameters::new(msg.id))
-                .await?;
+            bot.send_message(
+                chat_id,
+                format!(
+                    "Your message count: {}\nSpam classifier: {}\n{}",
+                    count,
+                    classifier.status(),
+                    state.describe_budget(chat_id, &settings.budget)
+                ),
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
         }
         Command::Save() => {
             if let Err(e) = state.save_to_file(&settings.state_path).await {
@@ -106,61 +215,691 @@ async fn handle_command(
                 .reply_parameters(ReplyParameters::new(msg.id))
                 .await?;
         }
+        Command::FailPolicy(policy) => {
+            let reply = match is_admin(&bot, chat_id, user_id).await {
+                Ok(true) => match policy.parse::<FailPolicy>() {
+                    Ok(policy) => {
+                        state.update_chat_config(chat_id, |c| c.fail_policy = Some(policy));
+                        format!("Fail policy set to {:?}.", policy)
+                    }
+                    Err(e) => e,
+                },
+                Ok(false) => "Only administrators can change the fail policy.".to_string(),
+                Err(e) => e,
+            };
+            bot.send_message(chat_id, reply)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+        }
+        Command::Policy() => {
+            let reply = match state.chat_config(chat_id).policy {
+                Some(policy) => format!("Moderation policy of this chat:\n{}", policy),
+                None => "This chat has no moderation policy.".to_string(),
+            };
+            bot.send_message(chat_id, reply)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+        }
+        Command::SetPolicy(policy) => {
+            let policy = policy.trim().to_string();
+            let reply = match is_admin(&bot, chat_id, user_id).await {
+                Ok(true) if policy.is_empty() => {
+                    "Usage: /set_policy <rules of this chat>".to_string()
+                }
+                Ok(true) if policy.chars().count() > MAX_POLICY_LEN => {
+                    format!("Policy is too long, at most {} characters.", MAX_POLICY_LEN)
+                }
+                Ok(true) => {
+                    state.update_chat_config(chat_id, |c| c.policy = Some(policy));
+                    "Moderation policy updated.".to_string()
+                }
+                Ok(false) => "Only administrators can change the policy.".to_string(),
+                Err(e) => e,
+            };
+            bot.send_message(chat_id, reply)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+        }
+        Command::Domains() => {
+            let config = state.chat_config(chat_id);
+            let list = |domains: &[String]| {
+                if domains.is_empty() {
+                    "none".to_string()
+                } else {
+                    domains.join(", ")
+                }
+            };
+            bot.send_message(
+                chat_id,
+                format!(
+                    "Allowed domains: {}\nDenied domains: {}",
+                    list(&config.allowed_domains),
+                    list(&config.denied_domains)
+                ),
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        }
+        Command::AllowDomain(ref domain)
+        | Command::DenyDomain(ref domain)
+        | Command::RemoveDomain(ref domain) => {
+            let reply = match (
+                is_admin(&bot, chat_id, user_id).await,
+                normalize_domain(domain),
+            ) {
+                (Ok(true), None) => {
+                    "Invalid domain, give a domain without a path, e.g. example.org.".to_string()
+                }
+                (Ok(true), Some(domain)) => {
+                    state.update_chat_config(chat_id, |c| {
+                        c.allowed_domains.retain(|d| *d != domain);
+                        c.denied_domains.retain(|d| *d != domain);
+                        match cmd {
+                            Command::AllowDomain(_) => c.allowed_domains.push(domain.clone()),
+                            Command::DenyDomain(_) => c.denied_domains.push(domain.clone()),
+                            _ => {}
+                        }
+                    });
+                    format!("Domain lists updated for {}.", domain)
+                }
+                (Ok(false), _) => "Only administrators can change the domain lists.".to_string(),
+                (Err(e), _) => e,
+            };
+            bot.send_message(chat_id, reply)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+        }
+        Command::Shadow(mode) => {
+            let mode = mode.trim();
+            let reply = if mode.is_empty() {
+                let stats = state
+                    .shadow_stats
+                    .get(&chat_id.0)
+                    .map(|s| *s.value())
+                    .unwrap_or_default();
+                format!(
+                    "Shadow mode: {:?}\nChecked: {}\nWould have deleted: {}\nWould have flagged: {}\nWould have failed closed: {}",
+                    shadow_mode(&state, &settings, chat_id),
+                    stats.checked,
+                    stats.would_delete,
+                    stats.would_flag,
+                    stats.would_fail_closed
+                )
+            } else {
+                match is_admin(&bot, chat_id, user_id).await {
+                    Ok(true) => match mode.parse::<ShadowMode>() {
+                        // Reports would go nowhere
+                        Ok(ShadowMode::Report) if settings.admin_chat_id.is_none() => {
+                            "No admin chat is configured to report to, use log instead.".to_string()
+                        }
+                        Ok(mode) => {
+                            state.update_chat_config(chat_id, |c| c.shadow_mode = Some(mode));
+                            // Start counting afresh for the new trial
+                            state.shadow_stats.remove(&chat_id.0);
+                            format!("Shadow mode set to {:?}.", mode)
+                        }
+                        Err(e) => e,
+                    },
+                    Ok(false) => "Only administrators can change the shadow mode.".to_string(),
+                    Err(e) => e,
+                }
+            };
+            bot.send_message(chat_id, reply)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+        }
+        Command::Usage() => {
+            let reply = match is_admin(&bot, chat_id, user_id).await {
+                Ok(true) => {
+                    let days = state.usage_report(chat_id, USAGE_REPORT_DAYS);
+                    let mut total = DailyUsage::default();
+                    let mut reply = format!(
+                        "Classifier usage of this chat in the last {} days:",
+                        USAGE_REPORT_DAYS
+                    );
+                    for (day, usage) in &days {
+                        total.merge(usage);
+                        reply += &format!(
+                            "\n{}: {} requests, {} prompt and {} completion tokens",
+                            day, usage.requests, usage.prompt_tokens, usage.completion_tokens
+                        );
+                    }
+                    reply += &format!(
+                        "\nTotal: {} requests, {} prompt and {} completion tokens",
+                        total.requests, total.prompt_tokens, total.completion_tokens
+                    );
+                    reply
+                }
+                Ok(false) => "Only administrators can see the usage.".to_string(),
+                Err(e) => e,
+            };
+            bot.send_message(chat_id, reply)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+        }
+        Command::ExportUsage() => match is_admin(&bot, chat_id, user_id).await {
+            Ok(true) => {
+                // Usage of other chats is only shared with the admin chat
+                let scope = (settings.admin_chat_id != Some(chat_id.0)).then_some(chat_id);
+                let csv = state.export_usage(scope);
+                bot.send_document(
+                    chat_id,
+                    InputFile::memory(csv.into_bytes()).file_name("usage.csv"),
+                )
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            }
+            Ok(false) => {
+                bot.send_message(chat_id, "Only administrators can export the usage.")
+                    .reply_parameters(ReplyParameters::new(msg.id))
+                    .await?;
+            }
+            Err(e) => {
+                bot.send_message(chat_id, e)
+                    .reply_parameters(ReplyParameters::new(msg.id))
+                    .await?;
+            }
+        },
+        Command::ClearPolicy() => {
+            let reply = match is_admin(&bot, chat_id, user_id).await {
+                Ok(true) => {
+                    state.update_chat_config(chat_id, |c| c.policy = None);
+                    "Moderation policy removed.".to_string()
+                }
+                Ok(false) => "Only administrators can change the policy.".to_string(),
+                Err(e) => e,
+            };
+            bot.send_message(chat_id, reply)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+        }
     }
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_spam_check(
     bot: Bot,
     msg: Message,
-    agent: Arc<Agent>,
+    classifier: Arc<dyn SpamClassifier>,
+    rules: Arc<Rules>,
+    cache: Arc<VerdictCache>,
+    semantic: Arc<SemanticIndex>,
     state: Arc<AppState>,
     settings: Arc<Settings>,
 ) -> ResponseResult<()> {
     let chat_id = msg.chat.id;
-    let user_id = match msg.from.as_ref() {
-        Some(u) => u.id,
-        None => return Ok(()),
+
+    // Channel posts forwarded into the linked discussion group and anonymous admins posting as
+    // the group itself
+    if msg.is_automatic_forward() || msg.sender_chat.as_ref().is_some_and(|c| c.id == chat_id) {
+        return Ok(());
+    }
+
+    // Join dates tell the classifier how new a sender is
+    if let Some(members) = msg.new_chat_members() {
+        for member in members {
+            state.record_join(chat_id, member.id, msg.date.timestamp());
+        }
+        return Ok(());
+    }
+    if let Some(member) = msg.left_chat_member() {
+        state.record_leave(chat_id, member.id);
+        return Ok(());
+    }
+
+    let Some(sender) = Sender::of(&msg) else {
+        return Ok(());
     };
 
-    // Only check spam for users who haven't reached the trusted threshold
-    if state.is_trusted_user(chat_id, user_id, settings.check_threshold) {
+    // Only check spam for senders who haven't reached the trusted threshold
+    if state.is_trusted_user(chat_id, sender, settings.check_threshold) {
+        if let Some(text) = detect::message_text(&msg) {
+            state.record_trusted_ham(chat_id, text);
+        }
         return Ok(());
     }
 
-    if msg.text().is_some() {
-        // Retrieve message history context
-        let context = state.get_context(chat_id);
+    // For edits, keep the version we saw first for the audit trail
+    let edited = msg.edit_date().is_some();
+    let original = if edited {
+        state.find_original(chat_id, msg.id)
+    } else {
+        state.record_original(chat_id, &msg);
+        None
+    };
 
-        match agent.check_spam(&msg, &context).await {
-            Ok(res) => {
-                if res.msg_type != MsgType::NotSpam {
-                    post::process_spam(&bot, &msg, res, state.clone()).await;
-                } else {
-                    // Only increment counter for non-spam messages
-                    state.increment(chat_id, user_id);
+    let Some(res) = classify(
+        &bot,
+        &msg,
+        classifier.as_ref(),
+        &rules,
+        &cache,
+        &semantic,
+        &state,
+        &settings,
+    )
+    .await
+    else {
+        // Nothing the classifier could look at (e.g. service messages)
+        return Ok(());
+    };
+
+    match res {
+        Ok(res) => apply_verdict(&bot, &msg, original.as_ref(), res, &state, &settings).await,
+        Err(e) => {
+            // On error, don't increment counter (be conservative)
+            let policy = if e.chain().any(|cause| cause.is::<BudgetExhausted>()) {
+                tracing::warn!("Skipping spam check: {}", e);
+                settings.budget.exhausted_policy
+            } else {
+                tracing::error!("Failed to check spam: {:#}", e);
+                state
+                    .chat_config(chat_id)
+                    .fail_policy
+                    .unwrap_or(settings.fail_policy)
+            };
+            let mode = shadow_mode(&state, &settings, chat_id);
+            match policy {
+                FailPolicy::Hold | FailPolicy::Delete if mode != ShadowMode::Off => {
+                    state.record_shadow_failure(chat_id);
+                    let outcome = if policy == FailPolicy::Hold {
+                        "hold the message for review as the spam check failed"
+                    } else {
+                        "delete the message as the spam check failed"
+                    };
+                    post::shadow_report(&bot, &settings, mode, &msg, outcome, None).await
                 }
-            }
-            Err(e) => {
-                tracing::error!("Failed to check spam: {}", e);
-                // On error, don't increment counter (be conservative)
+                FailPolicy::Open => {}
+                FailPolicy::Hold => post::hold_message(&bot, &msg, &state).await,
+                FailPolicy::Delete => {
+                    let res = SpamCheckResult {
+                        msg_type: MsgType::OtherSpam,
+                        confidence: 0.0,
+                        reason: "Spam check unavailable, failing closed".to_string(),
+                        usage: Default::default(),
+                        recalled: false,
+                    };
+                    post::process_spam(&bot, &msg, original.as_ref(), res, state.clone()).await
+                }
+                FailPolicy::Recheck => state.queue_recheck(chat_id, msg.clone()),
             }
         }
+    }
 
-        // Store message in history after processing (regardless of spam result)
+    // Store message in history after processing (regardless of spam result)
+    if edited {
+        state.replace_message(chat_id, msg, settings.context_messages);
+    } else {
         state.add_message(chat_id, msg, settings.context_messages);
     }
 
     Ok(())
 }
 
+/// Checks of the text answering without the classifier, if any of them is sure
+pub fn pre_filter(
+    chat_id: ChatId,
+    text: &str,
+    rules: &Rules,
+    state: &AppState,
+    settings: &Settings,
+) -> Option<SpamCheckResult> {
+    // Deterministic rules decide first, the classifier is only asked if none matched. Rules
+    // voting in an ensemble are asked along with the classifiers instead.
+    if !settings.rules_vote()
+        && let Some(msg_type) = rules.check(text)
+    {
+        return Some(SpamCheckResult::certain(
+            msg_type,
+            "Matched a pre-filter rule",
+        ));
+    }
+
+    // Campaigns change a few words per copy
+    if let Some((msg_type, similarity)) =
+        state.find_near_duplicate(chat_id, text, settings.near_duplicate_similarity)
+    {
+        return Some(SpamCheckResult {
+            msg_type,
+            confidence: similarity as f32,
+            reason: format!(
+                "Near-duplicate of known spam (similarity {:.2})",
+                similarity
+            ),
+            usage: Default::default(),
+            recalled: true,
+        });
+    }
+
+    // A confident local model saves the classifier request
+    if let Some(above) = settings.local_model.pre_filter_above
+        && let Some(probability) = state.local_spam_probability(text)
+        && probability >= above
+    {
+        return Some(detect::local_verdict(probability));
+    }
+
+    None
+}
+
+/// Run a message through the rules and the classifier.
+/// Returns `None` if the message has no content to check.
+#[allow(clippy::too_many_arguments)]
+async fn classify(
+    bot: &Bot,
+    msg: &Message,
+    classifier: &dyn SpamClassifier,
+    rules: &Rules,
+    cache: &VerdictCache,
+    semantic: &SemanticIndex,
+    state: &AppState,
+    settings: &Settings,
+) -> Option<anyhow::Result<SpamCheckResult>> {
+    let chat_id = msg.chat.id;
+    let text = detect::message_text(msg);
+
+    if let Some(res) = text.and_then(|t| pre_filter(chat_id, t, rules, state, settings)) {
+        return Some(Ok(res));
+    }
+
+    let chat_config = state.chat_config(chat_id);
+
+    // Domain allow and deny lists also apply to the targets of hidden links
+    let mut links = Links::extract(msg);
+    let allowed = settings
+        .allowed_domains
+        .iter()
+        .chain(&chat_config.allowed_domains)
+        .filter_map(|d| normalize_domain(d))
+        .collect::<Vec<_>>();
+    let denied = settings
+        .denied_domains
+        .iter()
+        .chain(&chat_config.denied_domains)
+        .filter_map(|d| normalize_domain(d))
+        .chain(rules.banned_domains().iter().cloned())
+        .collect::<Vec<_>>();
+    if let Some(res) = links.check_domains(&denied) {
+        return Some(Ok(res));
+    }
+    links.drop_allowed(&allowed);
+
+    // Identical content was classified recently, reuse the verdict
+    let media_id = media::file_unique_id(msg);
+    if text.is_none() && media_id.is_none() {
+        return None;
+    }
+    let policy = chat_config.policy.as_deref();
+    let sender = Sender::of(msg)
+        .map(|s| state.sender_profile(chat_id, s, msg.date.timestamp()))
+        .unwrap_or_default();
+    let key = VerdictCache::key(text, &links, media_id, policy, &sender);
+    if let Some(sender) = Sender::of(msg) {
+        cache.track_sender(chat_id, sender, key);
+    }
+    if let Some(res) = cache.get(key) {
+        tracing::debug!(
+            "Verdict cache hit for message {} in chat {}",
+            msg.id,
+            chat_id
+        );
+        return Some(Ok(res));
+    }
+
+    // Paraphrases of confirmed spam don't need the classifier, other close labeled messages
+    // are evidence for it
+    let similar = match text {
+        Some(text) => semantic.nearest(chat_id, text).await,
+        None => Vec::new(),
+    };
+    if let Some(nearest) = semantic.paraphrased_spam(&similar) {
+        let res = SpamCheckResult {
+            msg_type: nearest.label,
+            confidence: nearest.similarity,
+            reason: format!(
+                "Paraphrase of confirmed spam (similarity {:.2})",
+                nearest.similarity
+            ),
+            usage: Default::default(),
+            recalled: true,
+        };
+        cache.insert(key, res.clone());
+        return Some(Ok(res));
+    }
+
+    // Media that can't be downloaded (e.g. too large) still gets the message checked by its
+    // caption and metadata, or spammers would just post oversized files
+    let media = media::download(bot, msg, settings.max_media_bytes).await;
+
+    // Retrieve message history context
+    let context = state.get_context(chat_id);
+    let examples = state.few_shot_examples(chat_id, settings.few_shot_examples);
+    let res = classifier
+        .check_spam(&SpamCheckRequest {
+            message: msg,
+            context: &context,
+            media: media.as_ref(),
+            policy,
+            links: &links,
+            sender,
+            examples: &examples,
+            similar: &similar,
+        })
+        .await;
+
+    if let Ok(res) = &res {
+        cache.insert(key, res.clone());
+    }
+
+    // Keep track of the classifier health so that admins learn about outages. An exhausted
+    // budget is no outage.
+    let exhausted = res
+        .as_ref()
+        .is_err_and(|e| e.chain().any(|cause| cause.is::<BudgetExhausted>()));
+    if res.is_ok() {
+        let failures = state.record_classifier_success(chat_id);
+        if failures >= settings.failure_alert_threshold {
+            post::notify_admins(
+                bot,
+                settings,
+                chat_id,
+                format!(
+                    "Spam classifier recovered in chat {} after {} consecutive failures.",
+                    chat_id, failures
+                ),
+            )
+            .await;
+        }
+    } else if !exhausted {
+        let failures = state.record_classifier_failure(chat_id);
+        if failures == settings.failure_alert_threshold {
+            post::notify_admins(
+                bot,
+                settings,
+                chat_id,
+                format!(
+                    "Spam classifier has failed for {} consecutive messages in chat {}.",
+                    failures, chat_id
+                ),
+            )
+            .await;
+        }
+    }
+
+    // Rules voting in the ensemble still decide on their own once the budget runs out
+    let res = match res {
+        Err(e) if exhausted && settings.rules_vote() => {
+            match text.and_then(|text| rules.check(text)) {
+                Some(verdict) => Ok(SpamCheckResult::certain(
+                    verdict,
+                    "Matched a pre-filter rule",
+                )),
+                None => Err(e),
+            }
+        }
+        res => res,
+    };
+
+    Some(res)
+}
+
+fn shadow_mode(state: &AppState, settings: &Settings, chat_id: ChatId) -> ShadowMode {
+    state
+        .chat_config(chat_id)
+        .shadow_mode
+        .unwrap_or(settings.shadow_mode)
+}
+
+/// Act on a classifier verdict
+async fn apply_verdict(
+    bot: &Bot,
+    msg: &Message,
+    original: Option<&Message>,
+    res: SpamCheckResult,
+    state: &Arc<AppState>,
+    settings: &Settings,
+) {
+    let action = post::decide(&res, settings);
+
+    // In shadow mode, spam is only logged and reported
+    let mode = shadow_mode(state, settings, msg.chat.id);
+    if mode != ShadowMode::Off {
+        state.record_shadow(msg.chat.id, &action);
+        let outcome = match action {
+            Action::Delete => Some("delete the message and mute the sender"),
+            Action::Flag => Some("flag the message to admins"),
+            Action::Ignore => None,
+        };
+        if let Some(outcome) = outcome {
+            post::shadow_report(bot, settings, mode, msg, outcome, Some(&res)).await;
+            return;
+        }
+    }
+
+    // Kick, Dismiss and Ignore on the report label the message for few-shot examples and the
+    // semantic index
+    if action != Action::Ignore
+        && let (Some(sender), Some(text)) = (Sender::of(msg), detect::message_text(msg))
+    {
+        state.await_label(msg.chat.id, sender, text, res.msg_type);
+    }
+
+    match action {
+        Action::Delete => {
+            // Only unmistakable fraud is remembered without a human, verdicts recalled from
+            // known spam or the local model would only reinforce themselves
+            if !res.recalled
+                && matches!(res.msg_type, MsgType::Scam | MsgType::Phishing)
+                && res.confidence >= FINGERPRINT_CONFIDENCE
+                && let Some(text) = detect::message_text(msg)
+            {
+                state.add_spam_fingerprint(msg.chat.id, text, res.msg_type);
+            }
+            post::process_spam(bot, msg, original, res, state.clone()).await
+        }
+        Action::Flag => post::flag_spam(bot, msg, original, &res, state).await,
+        Action::Ignore => {
+            // Only increment counter for new non-spam messages, edits don't earn trust
+            if res.msg_type == MsgType::NotSpam
+                && msg.edit_date().is_none()
+                && let Some(sender) = Sender::of(msg)
+            {
+                state.increment(msg.chat.id, sender);
+            }
+        }
+    }
+}
+
+/// Periodically train the local model on the labels gathered since
+async fn retrain_loop(state: Arc<AppState>, settings: Arc<Settings>) {
+    let mut interval = time::interval(Duration::from_secs(
+        settings.local_model.retrain_interval_secs,
+    ));
+    // The first tick completes immediately. A model saved with the state is used until the
+    // next retraining instead of being replaced right away.
+    let (spam, ham) = state.local_model.read().unwrap().samples();
+    if spam > 0 && ham > 0 {
+        interval.tick().await;
+        tracing::info!(
+            "Using saved local model trained on {} spam and {} ham samples",
+            spam,
+            ham
+        );
+    }
+    loop {
+        interval.tick().await;
+        let (spam, ham) = state.retrain_local_model(settings.local_model.min_samples);
+        tracing::info!(
+            "Trained local model on {} spam and {} ham samples",
+            spam,
+            ham
+        );
+    }
+}
+
+/// Periodically classify again the messages queued by the `recheck` fail policy
+async fn recheck_loop(
+    bot: Bot,
+    classifier: Arc<dyn SpamClassifier>,
+    rules: Arc<Rules>,
+    cache: Arc<VerdictCache>,
+    semantic: Arc<SemanticIndex>,
+    state: Arc<AppState>,
+    settings: Arc<Settings>,
+) {
+    let mut interval = time::interval(Duration::from_secs(settings.recheck_interval_secs));
+    loop {
+        interval.tick().await;
+
+        let mut queued = state.take_recheck_queue().into_iter();
+        while let Some(msg) = queued.next() {
+            match classify(
+                &bot,
+                &msg,
+                classifier.as_ref(),
+                &rules,
+                &cache,
+                &semantic,
+                &state,
+                &settings,
+            )
+            .await
+            {
+                Some(Ok(res)) => apply_verdict(&bot, &msg, None, res, &state, &settings).await,
+                Some(Err(e)) => {
+                    tracing::error!("Recheck failed, keeping messages queued: {:#}", e);
+                    // The backend is still failing, put everything back and wait for the next round
+                    state.queue_recheck(msg.chat.id, msg);
+                    for msg in queued.by_ref() {
+                        state.queue_recheck(msg.chat.id, msg);
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// Check whether a user is an administrator of the chat
+async fn is_admin(bot: &Bot, chat_id: ChatId, user_id: UserId) -> Result<bool, String> {
+    let admins = bot
+        .get_chat_administrators(chat_id)
+        .await
+        .map_err(|_| "Failed to verify permissions".to_string())?;
+
+    Ok(admins.iter().any(|admin| admin.user.id == user_id))
+}
+
 async fn handle_callback_query(
     bot: Bot,
     q: CallbackQuery,
+    cache: Arc<VerdictCache>,
+    semantic: Arc<SemanticIndex>,
     state: Arc<AppState>,
     settings: Arc<Settings>,
 ) -> ResponseResult<()> {
-    match handle_callback_inner(&bot, &q, &state, &settings).await {
+    match handle_callback_inner(&bot, &q, &cache, &semantic, &state, &settings).await {
         Ok(msg) => {
             bot.answer_callback_query(&q.id).text(msg).await?;
         }
@@ -178,6 +917,8 @@ async fn handle_callback_query(
 async fn handle_callback_inner(
     bot: &Bot,
     q: &CallbackQuery,
+    cache: &VerdictCache,
+    semantic: &SemanticIndex,
     state: &AppState,
     settings: &Settings,
 ) -> Result<&'static str, String> {
@@ -186,53 +927,64 @@ async fn handle_callback_inner(
     let chat_id = message.chat().id;
     let clicker = q.from.id;
 
-    let (action, user_id_str) = data.split_once(':').ok_or("Invalid callback data")?;
-    let user_id_raw = user_id_str.parse::<u64>().map_err(|_| "Invalid user ID")?;
-    let banned_user_id = UserId(user_id_raw);
+    let (action, sender_str) = data.split_once(':').ok_or("Invalid callback data")?;
+    let sender = sender_str
+        .parse::<Sender>()
+        .map_err(|_| "Invalid sender ID")?;
 
     match action {
         "dismiss" => {
             handle_dismiss(
-                bot,
-                q,
-                state,
-                settings,
-                chat_id,
-                clicker,
-                banned_user_id,
-                message,
+                bot, q, cache, semantic, state, settings, chat_id, clicker, sender, message,
             )
             .await
         }
-        "kick" => handle_kick(bot, state, chat_id, clicker, banned_user_id, message).await,
+        "ignore" => {
+            handle_ignore(
+                bot, semantic, state, settings, chat_id, clicker, sender, message,
+            )
+            .await
+        }
+        "kick" => handle_kick(bot, semantic, state, chat_id, clicker, sender, message).await,
         _ => Err("Unknown action".to_string()),
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_dismiss(
     bot: &Bot,
     q: &CallbackQuery,
+    cache: &VerdictCache,
+    semantic: &SemanticIndex,
     state: &AppState,
     settings: &Settings,
     chat_id: ChatId,
     clicker: UserId,
-    banned_user_id: UserId,
+    banned: Sender,
     message: &teloxide::types::MaybeInaccessibleMessage,
 ) -> Result<&'static str, String> {
     if !state.is_trusted_user(chat_id, clicker, settings.check_threshold) {
         return Err("You must be a trusted user to dismiss this action".to_string());
     }
 
-    bot.restrict_chat_member(
-        chat_id,
-        banned_user_id,
-        teloxide::types::ChatPermissions::all(),
-    )
-    .await
+    match banned {
+        Sender::User(user_id) => {
+            bot.restrict_chat_member(chat_id, user_id, teloxide::types::ChatPermissions::all())
+                .await
+        }
+        Sender::Chat(sender_chat_id) => bot.unban_chat_sender_chat(chat_id, sender_chat_id).await,
+    }
     .map_err(|_| "Failed to unban user".to_string())?;
 
     let _ = bot.delete_message(chat_id, message.id()).await;
-    state.remove_spam_notification(chat_id, banned_user_id);
+    state.remove_spam_notification(chat_id, banned);
+    // Neither the verdict nor the campaign it was matched to were right
+    if let Some(example) = state.label_pending(chat_id, banned, false) {
+        state.remove_spam_fingerprints(chat_id, &example.text, settings.near_duplicate_similarity);
+        semantic.add(chat_id, &example.text, example.label).await;
+    }
+    // The verdict was wrong, don't apply it to copies of the message
+    cache.evict_sender(chat_id, banned);
 
     let clicker_name = format!(
         "{} {}",
@@ -249,47 +1001,248 @@ async fn handle_dismiss(
         .unwrap_or_else(|| "no username".to_string());
 
     tracing::info!(
-        "User {} ({}, {}) dismissed ban for user {} in chat {}",
+        "User {} ({}, {}) dismissed ban for {} in chat {}",
         clicker_name,
         clicker_username,
         clicker,
-        banned_user_id,
+        banned.label(),
         chat_id
     );
 
     Ok("User has been unbanned")
 }
 
-async fn handle_kick(
+#[allow(clippy::too_many_arguments)]
+async fn handle_ignore(
     bot: &Bot,
+    semantic: &SemanticIndex,
     state: &AppState,
+    settings: &Settings,
     chat_id: ChatId,
     clicker: UserId,
-    banned_user_id: UserId,
+    flagged: Sender,
     message: &teloxide::types::MaybeInaccessibleMessage,
 ) -> Result<&'static str, String> {
-    let admins = bot
-        .get_chat_administrators(chat_id)
-        .await
-        .map_err(|_| "Failed to verify permissions".to_string())?;
+    if !state.is_trusted_user(chat_id, clicker, settings.check_threshold) {
+        return Err("You must be a trusted user to ignore this report".to_string());
+    }
 
-    if !admins.iter().any(|admin| admin.user.id == clicker) {
+    let _ = bot.delete_message(chat_id, message.id()).await;
+    state.remove_spam_notification(chat_id, flagged);
+    // Trusted users saw nothing wrong with the message, the same as a dismissal
+    if let Some(example) = state.label_pending(chat_id, flagged, false) {
+        state.remove_spam_fingerprints(chat_id, &example.text, settings.near_duplicate_similarity);
+        semantic.add(chat_id, &example.text, example.label).await;
+    }
+
+    tracing::info!(
+        "User {} ignored the spam report for {} in chat {}",
+        clicker,
+        flagged.label(),
+        chat_id
+    );
+
+    Ok("Report has been ignored")
+}
+
+async fn handle_kick(
+    bot: &Bot,
+    semantic: &SemanticIndex,
+    state: &AppState,
+    chat_id: ChatId,
+    clicker: UserId,
+    banned: Sender,
+    message: &teloxide::types::MaybeInaccessibleMessage,
+) -> Result<&'static str, String> {
+    if !is_admin(bot, chat_id, clicker).await? {
         return Err("Only administrators can kick users".to_string());
     }
 
-    bot.ban_chat_member(chat_id, banned_user_id)
-        .await
-        .map_err(|_| "Failed to kick user".to_string())?;
+    match banned {
+        Sender::User(user_id) => bot.ban_chat_member(chat_id, user_id).await,
+        Sender::Chat(sender_chat_id) => bot.ban_chat_sender_chat(chat_id, sender_chat_id).await,
+    }
+    .map_err(|_| "Failed to kick user".to_string())?;
 
     let _ = bot.delete_message(chat_id, message.id()).await;
-    state.remove_spam_notification(chat_id, banned_user_id);
+    state.remove_spam_notification(chat_id, banned);
+    if let Some(example) = state.label_pending(chat_id, banned, true) {
+        state.add_spam_fingerprint(chat_id, &example.text, example.label);
+        semantic.add(chat_id, &example.text, example.label).await;
+    }
 
     tracing::info!(
-        "User {} kicked user {} from chat {}",
+        "User {} kicked {} from chat {}",
         clicker,
-        banned_user_id,
+        banned.label(),
         chat_id
     );
 
     Ok("User has been permanently kicked")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MockGemini, Reply, text_message};
+
+    /// Run a message through the whole pipeline. Telegram is unreachable, so any action beyond
+    /// bookkeeping would only log an error.
+    async fn run(mock: &MockGemini, settings: Settings, state: &Arc<AppState>, msg: Message) {
+        let bot = Bot::new("test").set_api_url("http://127.0.0.1:9".parse().unwrap());
+        let settings = Arc::new(Settings {
+            classifier: mock.settings(),
+            ..settings
+        });
+        handle_spam_check(
+            bot,
+            msg,
+            detect::build_classifier(&settings, Arc::new(Rules::default()), state.clone()).unwrap(),
+            Arc::new(Rules::default()),
+            Arc::new(VerdictCache::new(Duration::from_secs(60), 10)),
+            Arc::new(SemanticIndex::from_settings(&settings, state.clone()).unwrap()),
+            state.clone(),
+            settings,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn test_argument_commands_are_admin_only() {
+        for text in [
+            "/fail_policy t.me/scam",
+            "/set_policy Free crypto at t.me/scam",
+            "/allow_domain t.me/scam",
+            "/deny_domain t.me/scam",
+            "/remove_domain t.me/scam",
+            "/shadow Free crypto at t.me/scam",
+            "/shadow",
+            "/stats",
+        ] {
+            let cmd = Command::parse(text, "bot").unwrap();
+            assert_eq!(cmd.takes_argument(), text.contains(' '), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        let mut settings = test_support::settings(Default::default());
+        assert!(settings.validate().is_ok());
+        settings.local_model.retrain_interval_secs = 0;
+        assert!(settings.validate().is_err());
+
+        let mut settings = test_support::settings(Default::default());
+        settings.recheck_interval_secs = 0;
+        assert!(settings.validate().is_err());
+
+        let mut settings = test_support::settings(Default::default());
+        settings.failure_alert_threshold = 0;
+        assert!(settings.validate().is_err());
+
+        let mut settings = test_support::settings(Default::default());
+        settings.resilience.max_in_flight = 0;
+        assert!(settings.validate().is_err());
+
+        let mut settings = test_support::settings(Default::default());
+        settings.shadow_mode = ShadowMode::Report;
+        assert!(settings.validate().is_err());
+        settings.admin_chat_id = Some(-1);
+        assert!(settings.validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_against_mock_server() {
+        let mut settings = test_support::settings(Default::default());
+        settings.resilience.max_retries = 0;
+        let chat_id = ChatId(-100);
+
+        // Ham earns trust
+        let state = Arc::new(AppState::new());
+        let mock = MockGemini::start(vec![Reply::Verdict(MsgType::NotSpam, 0.9)]).await;
+        run(&mock, settings.clone(), &state, text_message(1, 1, "hello")).await;
+        assert_eq!(state.get_count(chat_id, UserId(1)), 1);
+
+        // Failing open lets the message through without earning trust
+        let state = Arc::new(AppState::new());
+        let mock = MockGemini::start(vec![Reply::Status(503)]).await;
+        run(&mock, settings.clone(), &state, text_message(1, 1, "hello")).await;
+        assert_eq!(state.get_count(chat_id, UserId(1)), 0);
+        assert_eq!(state.get_context(chat_id).len(), 1);
+        assert!(state.take_recheck_queue().is_empty());
+
+        // Malformed output follows the fail policy as well
+        let state = Arc::new(AppState::new());
+        let mock = MockGemini::start(vec![Reply::Text("{\"msg_type\": ".to_string())]).await;
+        settings.fail_policy = FailPolicy::Recheck;
+        run(&mock, settings.clone(), &state, text_message(1, 1, "hello")).await;
+        assert_eq!(state.get_count(chat_id, UserId(1)), 0);
+        assert_eq!(state.take_recheck_queue().len(), 1);
+
+        // An exhausted budget is no classifier outage
+        let state = Arc::new(AppState::new());
+        let mock = MockGemini::start(Vec::new()).await;
+        let mut limited = settings.clone();
+        limited.budget.per_chat.requests_per_minute = Some(0);
+        run(&mock, limited, &state, text_message(1, 1, "hello")).await;
+        assert!(mock.requests().is_empty());
+        assert!(state.classifier_failures.get(&chat_id.0).is_none());
+
+        // Shadow mode only counts what would have been done
+        let state = Arc::new(AppState::new());
+        state.update_chat_config(chat_id, |c| c.shadow_mode = Some(ShadowMode::Log));
+        let mock = MockGemini::start(vec![Reply::Verdict(MsgType::Scam, 0.9)]).await;
+        run(
+            &mock,
+            settings.clone(),
+            &state,
+            text_message(1, 1, "Free crypto"),
+        )
+        .await;
+        let stats = *state.shadow_stats.get(&chat_id.0).unwrap();
+        assert_eq!((stats.checked, stats.would_delete), (1, 1));
+        assert!(state.get_spam_notification(chat_id, UserId(1)).is_none());
+
+        // Copies of known spam refresh its fingerprint rather than adding their own
+        let state = Arc::new(AppState::new());
+        let known = "Hello everyone! I made 5000$ last week trading with the help of Mrs. Anna, \
+                     message her on Telegram to start earning today, limited spots";
+        state.add_spam_fingerprint(chat_id, known, MsgType::Scam);
+        for (_, seen) in state
+            .spam_fingerprints
+            .get_mut(&chat_id.0)
+            .unwrap()
+            .values_mut()
+        {
+            *seen = 0;
+        }
+        let mock = MockGemini::start(Vec::new()).await;
+        settings.thresholds = serde_json::from_value(serde_json::json!({
+            "scam": {"delete": 0.5, "flag": 0.5},
+        }))
+        .unwrap();
+        run(
+            &mock,
+            settings.clone(),
+            &state,
+            text_message(
+                1,
+                1,
+                "Hi everyone! I made 7000$ last week trading with the help of Mrs. Anna, message \
+                 her on telegram to start earning now, limited spots",
+            ),
+        )
+        .await;
+        assert!(mock.requests().is_empty());
+        let fingerprints = state.spam_fingerprints.get(&chat_id.0).unwrap();
+        assert_eq!(fingerprints.len(), 1);
+        assert!(fingerprints.values().all(|(_, seen)| *seen > 0));
+        drop(fingerprints);
+        // Other chats, with other policies, don't share it
+        assert!(
+            state
+                .find_near_duplicate(ChatId(-200), known, 0.5)
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use crate::config::BudgetLimits;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const MINUTE: i64 = 60;
+const DAY: i64 = 24 * 60 * MINUTE;
+
+/// Usage within one fixed time window
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+struct Window {
+    /// Number of the window since the epoch
+    index: i64,
+    requests: u64,
+    tokens: u64,
+}
+
+impl Window {
+    fn roll(&mut self, index: i64) {
+        if self.index != index {
+            *self = Self {
+                index,
+                ..Default::default()
+            };
+        }
+    }
+}
+
+/// Classifier requests and tokens spent in the current minute and day
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct BudgetUsage {
+    minute: Window,
+    day: Window,
+}
+
+impl BudgetUsage {
+    /// Start new windows if the current ones are over, `now` is a unix timestamp
+    fn roll(&mut self, now: i64) {
+        self.minute.roll(now.div_euclid(MINUTE));
+        self.day.roll(now.div_euclid(DAY));
+    }
+
+    /// The first limit that has been reached, if any
+    pub fn exhausted(&mut self, limits: &BudgetLimits, now: i64) -> Option<&'static str> {
+        self.roll(now);
+        [
+            (
+                self.minute.requests,
+                limits.requests_per_minute,
+                "requests per minute",
+            ),
+            (
+                self.day.requests,
+                limits.requests_per_day,
+                "requests per day",
+            ),
+            (
+                self.minute.tokens,
+                limits.tokens_per_minute,
+                "tokens per minute",
+            ),
+            (self.day.tokens, limits.tokens_per_day, "tokens per day"),
+        ]
+        .into_iter()
+        .find(|(used, limit, _)| limit.is_some_and(|limit| *used >= limit))
+        .map(|(_, _, name)| name)
+    }
+
+    pub fn spend_request(&mut self, now: i64) {
+        self.roll(now);
+        self.minute.requests += 1;
+        self.day.requests += 1;
+    }
+
+    /// Tokens are only known once the response arrived, so they are counted after the fact
+    pub fn spend_tokens(&mut self, tokens: u64, now: i64) {
+        self.roll(now);
+        self.minute.tokens += tokens;
+        self.day.tokens += tokens;
+    }
+
+    /// Usage against the limits
+    pub fn describe(mut self, limits: &BudgetLimits, now: i64) -> String {
+        self.roll(now);
+        let used = |used: u64, limit: Option<u64>| match limit {
+            Some(limit) => format!("{}/{}", used, limit),
+            None => used.to_string(),
+        };
+        format!(
+            "{} requests and {} tokens this minute, {} requests and {} tokens today",
+            used(self.minute.requests, limits.requests_per_minute),
+            used(self.minute.tokens, limits.tokens_per_minute),
+            used(self.day.requests, limits.requests_per_day),
+            used(self.day.tokens, limits.tokens_per_day),
+        )
+    }
+}
+
+/// Returned instead of a verdict when a classifier budget has run out
+#[derive(Debug)]
+pub struct BudgetExhausted {
+    pub scope: String,
+    pub limit: &'static str,
+}
+
+impl fmt::Display for BudgetExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Classifier budget of {} exhausted ({})",
+            self.scope, self.limit
+        )
+    }
+}
+
+impl std::error::Error for BudgetExhausted {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_windows() {
+        let limits = BudgetLimits {
+            requests_per_minute: Some(2),
+            tokens_per_day: Some(100),
+            ..Default::default()
+        };
+        let mut usage = BudgetUsage::default();
+        let now = 10 * DAY;
+
+        usage.spend_request(now);
+        assert_eq!(usage.exhausted(&limits, now), None);
+        usage.spend_request(now + 1);
+        assert_eq!(
+            usage.exhausted(&limits, now + 2),
+            Some("requests per minute")
+        );
+
+        // A new minute resets the request limit but not the daily tokens
+        usage.spend_tokens(100, now + 3);
+        assert_eq!(
+            usage.exhausted(&limits, now + MINUTE),
+            Some("tokens per day")
+        );
+        assert_eq!(usage.exhausted(&limits, now + DAY), None);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::detect::{SenderProfile, SpamCheckResult};
+use crate::links::Links;
+use crate::normalize;
+use crate::sender::Sender;
+use crate::state::AppState;
+use dashmap::DashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+use teloxide::types::ChatId;
+
+/// Cache keys remembered per sender. A sender posting more distinct messages within the TTL is
+/// spamming anyway.
+const MAX_KEYS_PER_SENDER: usize = 16;
+
+/// Bounded cache of classifier verdicts keyed by message content, so that floods of the same
+/// message only cost one classifier request
+pub struct VerdictCache {
+    entries: DashMap<u64, (SpamCheckResult, Instant)>,
+    /// Cache keys of the recent messages of each sender and when the last one was checked,
+    /// key: "sender:chat_id"
+    sender_keys: DashMap<String, (Vec<u64>, Instant)>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl VerdictCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            entries: DashMap::new(),
+            sender_keys: DashMap::new(),
+            ttl,
+            capacity,
+        }
+    }
+
+    /// Compute the cache key of a message from its text, link targets and media. Chats with
+    /// their own policy get separate entries since the same content may be judged differently
+    /// there, and so do senders of different standing. Their history is rounded to powers of
+    /// two, so that floods from fresh accounts still share an entry.
+    pub fn key(
+        text: Option<&str>,
+        links: &Links,
+        media_unique_id: Option<&str>,
+        policy: Option<&str>,
+        sender: &SenderProfile,
+    ) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        // Obfuscated variations of a message share an entry
+        text.map(normalize::skeleton).hash(&mut hasher);
+        // Hidden links don't show up in the text
+        for link in &links.links {
+            link.url.hash(&mut hasher);
+        }
+        media_unique_id.hash(&mut hasher);
+        policy.hash(&mut hasher);
+        (sender.message_count + 1).ilog2().hash(&mut hasher);
+        sender
+            .member_for
+            .map(|d| (d.num_minutes().max(0) + 1).ilog2())
+            .hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn get(&self, key: u64) -> Option<SpamCheckResult> {
+        let entry = self.entries.get(&key)?;
+        let (res, inserted) = entry.value();
+        if inserted.elapsed() < self.ttl {
+            return Some(res.clone());
+        }
+
+        drop(entry);
+        self.entries.remove(&key);
+        None
+    }
+
+    pub fn insert(&self, key: u64, res: SpamCheckResult) {
+        if self.entries.len() >= self.capacity {
+            self.entries
+                .retain(|_, (_, inserted)| inserted.elapsed() < self.ttl);
+        }
+
+        // Still full of fresh entries, make room by dropping the oldest one
+        if self.entries.len() >= self.capacity
+            && let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|e| e.value().1)
+                .map(|e| *e.key())
+        {
+            self.entries.remove(&oldest);
+        }
+
+        self.entries.insert(key, (res, Instant::now()));
+    }
+
+    /// Remember which content a sender posted, so that a later dismissal can find it
+    pub fn track_sender(&self, chat_id: ChatId, sender: Sender, key: u64) {
+        let sender_key = AppState::key(chat_id, sender);
+        let full =
+            self.sender_keys.len() >= self.capacity && !self.sender_keys.contains_key(&sender_key);
+
+        // Senders whose verdicts all expired have nothing left to evict
+        if full {
+            self.sender_keys
+                .retain(|_, (_, checked)| checked.elapsed() < self.ttl);
+        }
+        if full
+            && self.sender_keys.len() >= self.capacity
+            && let Some(oldest) = self
+                .sender_keys
+                .iter()
+                .min_by_key(|e| e.value().1)
+                .map(|e| e.key().clone())
+        {
+            self.sender_keys.remove(&oldest);
+        }
+
+        let mut entry = self
+            .sender_keys
+            .entry(sender_key)
+            .or_insert_with(|| (Vec::new(), Instant::now()));
+        let (keys, checked) = entry.value_mut();
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+        if keys.len() > MAX_KEYS_PER_SENDER {
+            keys.remove(0);
+        }
+        *checked = Instant::now();
+    }
+
+    /// Drop the cached verdicts of the recent messages of a sender, e.g. after a dismissed ban
+    pub fn evict_sender(&self, chat_id: ChatId, sender: Sender) {
+        if let Some((_, (keys, _))) = self.sender_keys.remove(&AppState::key(chat_id, sender)) {
+            for key in keys {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detect::MsgType;
+    use teloxide::types::UserId;
+
+    #[test]
+    fn test_cache_lookup_and_eviction() {
+        let cache = VerdictCache::new(Duration::from_secs(60), 2);
+        let links = Links::default();
+        let sender = SenderProfile::default();
+        let key = VerdictCache::key(Some("Buy  NOW"), &links, None, None, &sender);
+
+        assert_eq!(
+            key,
+            VerdictCache::key(Some("buy now"), &links, None, None, &sender)
+        );
+        assert_eq!(
+            key,
+            VerdictCache::key(Some("Buу\u{200B} ΝOW"), &links, None, None, &sender)
+        );
+        assert_ne!(
+            key,
+            VerdictCache::key(Some("buy now"), &links, Some("photo"), None, &sender)
+        );
+        assert_ne!(
+            key,
+            VerdictCache::key(Some("buy now"), &links, None, Some("ads ok"), &sender)
+        );
+
+        // Verdicts on a newcomer don't carry over to a regular
+        let regular = SenderProfile {
+            message_count: 40,
+            member_for: Some(chrono::Duration::days(90)),
+        };
+        assert_ne!(
+            key,
+            VerdictCache::key(Some("buy now"), &links, None, None, &regular)
+        );
+        let newcomer = |minutes| SenderProfile {
+            message_count: 0,
+            member_for: Some(chrono::Duration::minutes(minutes)),
+        };
+        assert_eq!(
+            VerdictCache::key(Some("buy now"), &links, None, None, &newcomer(4)),
+            VerdictCache::key(Some("buy now"), &links, None, None, &newcomer(6))
+        );
+
+        cache.insert(key, SpamCheckResult::certain(MsgType::Scam, ""));
+        assert_eq!(cache.get(key).unwrap().msg_type, MsgType::Scam);
+
+        // Every message of a dismissed sender is evicted, not just the last one
+        cache.insert(7, SpamCheckResult::certain(MsgType::Scam, ""));
+        cache.track_sender(ChatId(1), UserId(2).into(), key);
+        cache.track_sender(ChatId(1), UserId(2).into(), 7);
+        cache.evict_sender(ChatId(1), UserId(2).into());
+        assert!(cache.get(key).is_none());
+        assert!(cache.get(7).is_none());
+
+        // Senders are bounded by the capacity as well
+        for user in 0..5 {
+            cache.track_sender(ChatId(1), UserId(user).into(), 1);
+        }
+        assert_eq!(cache.sender_keys.len(), 2);
+
+        // Capacity is enforced by dropping the oldest entry
+        cache.insert(1, SpamCheckResult::certain(MsgType::Scam, ""));
+        cache.insert(2, SpamCheckResult::certain(MsgType::Scam, ""));
+        cache.insert(3, SpamCheckResult::certain(MsgType::Scam, ""));
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(3).is_some());
+    }
+}
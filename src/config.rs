@@ -1,9 +1,16 @@
+use crate::detect::MsgType;
+use crate::links::normalize_domain;
 use config::{Config, File};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
+    /// Required to run the bot, not for evaluations
+    #[serde(default)]
     pub tg_bot_token: String,
+    #[serde(default)]
     pub gemini_api_key: String,
     #[serde(default = "default_threshold")]
     pub check_threshold: u64,
@@ -11,6 +18,323 @@ pub struct Settings {
     pub state_path: String,
     #[serde(default = "default_context_messages")]
     pub context_messages: usize,
+    /// Messages labeled by admins and trusted users shown to the classifier as examples
+    #[serde(default = "default_few_shot_examples")]
+    pub few_shot_examples: usize,
+    #[serde(default)]
+    pub classifier: ClassifierSettings,
+    /// Stronger classifier re-checking uncertain and spam verdicts of `classifier` before acting
+    pub escalation: Option<EscalationSettings>,
+    /// Several classifiers voting on each message, used in place of `classifier`
+    pub ensemble: Option<EnsembleSettings>,
+    /// Path to a TOML file with pre-filter rules evaluated before the classifier
+    pub rules_path: Option<String>,
+    /// Largest media file (in bytes) downloaded and sent to the classifier
+    #[serde(default = "default_max_media_bytes")]
+    pub max_media_bytes: u32,
+    /// Per-category confidence thresholds, categories not listed are always acted upon
+    #[serde(default)]
+    pub thresholds: HashMap<MsgType, Threshold>,
+    /// What to do with a message when the classifier fails, unless overridden per chat
+    #[serde(default)]
+    pub fail_policy: FailPolicy,
+    /// Whether chats only log verdicts instead of acting on them, unless overridden per chat
+    #[serde(default)]
+    pub shadow_mode: ShadowMode,
+    /// Notify admins after this many consecutive classifier failures in a chat
+    #[serde(default = "default_failure_alert_threshold")]
+    pub failure_alert_threshold: u64,
+    /// How often messages queued by the `recheck` fail policy are retried
+    #[serde(default = "default_recheck_interval_secs")]
+    pub recheck_interval_secs: u64,
+    /// Chat receiving admin notifications. Defaults to the chat the event happened in.
+    pub admin_chat_id: Option<i64>,
+    #[serde(default)]
+    pub resilience: ResilienceSettings,
+    #[serde(default)]
+    pub local_model: LocalModelSettings,
+    /// Compare messages by meaning with confirmed spam and ham
+    pub embedding: Option<EmbeddingSettings>,
+    /// Messages at least this similar (0.0 to 1.0) to confirmed spam are treated as copies of
+    /// it, 1.0 only matches identical fingerprints
+    #[serde(default = "default_near_duplicate_similarity")]
+    pub near_duplicate_similarity: f64,
+    /// How long classifier verdicts are reused for identical messages
+    #[serde(default = "default_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+    /// Maximum number of cached verdicts
+    #[serde(default = "default_cache_capacity")]
+    pub cache_capacity: usize,
+    /// Links to these domains (or subdomains) are not held against a message, the rest of it is
+    /// still checked
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    /// Messages linking to these domains (or subdomains) are considered phishing
+    #[serde(default)]
+    pub denied_domains: Vec<String>,
+    #[serde(default)]
+    pub budget: BudgetSettings,
+    /// How many days of per-chat token usage are kept for reports and exports
+    #[serde(default = "default_usage_retention_days")]
+    pub usage_retention_days: i64,
+}
+
+/// Limits on classifier usage, so that no chat can spend the API quota unchecked
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct BudgetSettings {
+    /// Limits applying to each chat separately
+    pub per_chat: BudgetLimits,
+    /// Limits applying to all chats together
+    pub global: BudgetLimits,
+    /// What to do with messages not matched by the rules once a budget is exhausted
+    pub exhausted_policy: FailPolicy,
+}
+
+/// Request and token limits, unset limits are unlimited. Minutes and days are in UTC.
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(default)]
+pub struct BudgetLimits {
+    pub requests_per_minute: Option<u64>,
+    pub requests_per_day: Option<u64>,
+    pub tokens_per_minute: Option<u64>,
+    pub tokens_per_day: Option<u64>,
+}
+
+/// Timeouts, retries and circuit breaking applied to classifier backends
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ResilienceSettings {
+    /// Timeout of a single classifier request, including waiting for a free slot
+    pub timeout_secs: u64,
+    /// Retries after a transient failure (timeout, connection error, 429, 5xx)
+    pub max_retries: u32,
+    /// Base and maximum delay of the exponential backoff between retries
+    pub backoff_base_ms: u64,
+    pub backoff_max_ms: u64,
+    /// Maximum number of concurrent requests to a backend
+    pub max_in_flight: usize,
+    /// Consecutive failures after which the backend is considered down
+    pub circuit_failure_threshold: u32,
+    /// How long requests are skipped once the backend is considered down
+    pub circuit_cooldown_secs: u64,
+}
+
+impl Default for ResilienceSettings {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 20,
+            max_retries: 2,
+            backoff_base_ms: 500,
+            backoff_max_ms: 5000,
+            max_in_flight: 16,
+            circuit_failure_threshold: 5,
+            circuit_cooldown_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingBackend {
+    #[default]
+    Gemini,
+    /// Hashed character n-grams computed in process, free but only lexical
+    Local,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct EmbeddingSettings {
+    pub backend: EmbeddingBackend,
+    pub model: Option<String>,
+    /// Falls back to `gemini_api_key`
+    pub api_key: Option<String>,
+    pub base_url: Option<String>,
+    /// Messages at least this similar to confirmed spam are treated as spam without asking the
+    /// classifier
+    pub spam_above: f32,
+    /// Labeled messages at least this similar are shown to the classifier as evidence
+    pub evidence_above: f32,
+    /// Maximum number of labeled messages shown to the classifier
+    pub neighbours: usize,
+    /// Maximum number of labeled messages indexed per chat
+    pub capacity: usize,
+}
+
+impl Default for EmbeddingSettings {
+    fn default() -> Self {
+        Self {
+            backend: EmbeddingBackend::default(),
+            model: None,
+            api_key: None,
+            base_url: None,
+            spam_above: 0.95,
+            evidence_above: 0.8,
+            neighbours: 3,
+            capacity: 5000,
+        }
+    }
+}
+
+/// Naive Bayes model trained from Kick and Dismiss labels and messages of trusted users
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LocalModelSettings {
+    /// Treat messages as spam without asking the classifier from this spam probability on
+    pub pre_filter_above: Option<f64>,
+    pub retrain_interval_secs: u64,
+    /// Spam and ham samples each needed before the model is used
+    pub min_samples: u64,
+}
+
+impl Default for LocalModelSettings {
+    fn default() -> Self {
+        Self {
+            pre_filter_above: None,
+            retrain_interval_secs: 3600,
+            min_samples: 20,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailPolicy {
+    /// Let the message through
+    #[default]
+    Open,
+    /// Mute the sender and ask admins to review the message
+    Hold,
+    /// Treat the message as spam
+    Delete,
+    /// Queue the message and classify it again later
+    Recheck,
+}
+
+impl FromStr for FailPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(Self::Open),
+            "hold" => Ok(Self::Hold),
+            "delete" => Ok(Self::Delete),
+            "recheck" => Ok(Self::Recheck),
+            _ => Err(format!(
+                "Unknown fail policy `{}`, expected one of: open, hold, delete, recheck",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShadowMode {
+    /// Act on verdicts
+    #[default]
+    Off,
+    /// Only log what would have been done
+    Log,
+    /// Log and report what would have been done to the admin chat
+    Report,
+}
+
+impl FromStr for ShadowMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "log" => Ok(Self::Log),
+            "report" => Ok(Self::Report),
+            _ => Err(format!(
+                "Unknown shadow mode `{}`, expected one of: off, log, report",
+                s
+            )),
+        }
+    }
+}
+
+/// Confidence thresholds of a category. Categories without thresholds are always deleted.
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+pub struct Threshold {
+    /// Minimum confidence to delete the message and restrict the sender. If only `flag` is
+    /// set, only certain verdicts are deleted.
+    #[serde(default = "default_delete_threshold")]
+    pub delete: f32,
+    /// Minimum confidence to flag the message to admins without acting on it
+    #[serde(default)]
+    pub flag: f32,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    #[default]
+    Gemini,
+    #[serde(rename = "openai")]
+    OpenAi,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ClassifierSettings {
+    #[serde(default)]
+    pub backend: Backend,
+    /// API base URL, e.g. `http://localhost:8080/v1` for a llama.cpp server.
+    /// Defaults to the official endpoint for Gemini.
+    pub base_url: Option<String>,
+    /// API key. The Gemini backend falls back to `gemini_api_key`.
+    pub api_key: Option<String>,
+    /// Model name, e.g. `gemini-2.5-flash-lite`. Required for the OpenAI-compatible backend,
+    /// defaults to `gemini-3-flash-preview` for Gemini.
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct EnsembleSettings {
+    pub voters: Vec<VoterSettings>,
+    /// Let the rules vote with this weight, instead of deciding on their own before the
+    /// classifiers are asked
+    pub rules_weight: Option<f32>,
+    /// Let the local model vote with this weight
+    pub local_weight: Option<f32>,
+    #[serde(default)]
+    pub voting: Voting,
+    /// Share of the weight of all answering voters that must vote spam under weighted voting
+    #[serde(default = "default_weighted_threshold")]
+    pub weighted_threshold: f32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct VoterSettings {
+    pub classifier: ClassifierSettings,
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+}
+
+/// How the votes of an ensemble are combined into a spam verdict. Voters that fail or abstain
+/// are left out.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Voting {
+    /// Every voter must vote spam
+    Unanimous,
+    /// More than half of the voters must vote spam
+    #[default]
+    Majority,
+    /// The weight of the voters voting spam must reach `weighted_threshold`
+    Weighted,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct EscalationSettings {
+    pub classifier: ClassifierSettings,
+    /// Verdicts of the first classifier below this confidence are re-checked, spam verdicts
+    /// always are
+    #[serde(default = "default_uncertain_below")]
+    pub uncertain_below: f32,
 }
 
 fn default_state_path() -> String {
@@ -25,13 +349,127 @@ fn default_context_messages() -> usize {
     5
 }
 
+fn default_max_media_bytes() -> u32 {
+    5 * 1024 * 1024
+}
+
+fn default_failure_alert_threshold() -> u64 {
+    5
+}
+
+fn default_recheck_interval_secs() -> u64 {
+    60
+}
+
+fn default_near_duplicate_similarity() -> f64 {
+    0.9
+}
+
+fn default_few_shot_examples() -> usize {
+    6
+}
+
+fn default_delete_threshold() -> f32 {
+    1.0
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+fn default_weighted_threshold() -> f32 {
+    0.5
+}
+
+fn default_usage_retention_days() -> i64 {
+    90
+}
+
+fn default_uncertain_below() -> f32 {
+    0.8
+}
+
+fn default_cache_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_cache_capacity() -> usize {
+    10_000
+}
+
 impl Settings {
+    /// Whether the rules vote in an ensemble rather than deciding before the classifier
+    pub fn rules_vote(&self) -> bool {
+        self.ensemble
+            .as_ref()
+            .is_some_and(|e| e.rules_weight.is_some())
+    }
+
+    pub fn threshold(&self, msg_type: MsgType) -> Threshold {
+        self.thresholds.get(&msg_type).copied().unwrap_or_default()
+    }
+
     pub fn new() -> anyhow::Result<Self> {
         let s = Config::builder()
             .add_source(File::with_name("settings").required(false))
             .add_source(config::Environment::with_prefix("ANTISPAM").separator("__"))
             .build()?;
 
-        s.try_deserialize().map_err(Into::into)
+        let settings: Self = s.try_deserialize()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (msg_type, threshold) in &self.thresholds {
+            anyhow::ensure!(
+                threshold.flag <= threshold.delete,
+                "The flag threshold of {:?} is above its delete threshold",
+                msg_type
+            );
+        }
+        if let Some(ensemble) = &self.ensemble {
+            anyhow::ensure!(
+                ensemble.weighted_threshold > 0.0 && ensemble.weighted_threshold <= 1.0,
+                "The weighted threshold of the ensemble must be above 0 and at most 1"
+            );
+            let weights = ensemble
+                .voters
+                .iter()
+                .map(|v| v.weight)
+                .chain(ensemble.rules_weight)
+                .chain(ensemble.local_weight);
+            for weight in weights {
+                anyhow::ensure!(weight > 0.0, "Ensemble weights must be above 0");
+            }
+        }
+        for domain in self.allowed_domains.iter().chain(&self.denied_domains) {
+            anyhow::ensure!(
+                normalize_domain(domain).is_some(),
+                "Invalid domain {:?}, domain lists take domains without a path",
+                domain
+            );
+        }
+        anyhow::ensure!(
+            self.shadow_mode != ShadowMode::Report || self.admin_chat_id.is_some(),
+            "The report shadow mode needs an admin chat"
+        );
+        anyhow::ensure!(
+            self.failure_alert_threshold > 0,
+            "The failure alert threshold must be above 0"
+        );
+        anyhow::ensure!(
+            self.recheck_interval_secs > 0,
+            "The recheck interval must be above 0"
+        );
+        anyhow::ensure!(
+            self.resilience.max_in_flight > 0,
+            "The maximum number of requests in flight must be above 0"
+        );
+        anyhow::ensure!(
+            self.local_model.retrain_interval_secs > 0,
+            "The retrain interval of the local model must be above 0"
+        );
+        Ok(())
     }
 }
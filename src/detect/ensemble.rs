@@ -0,0 +1,321 @@
+use super::{MsgType, SpamCheckRequest, SpamCheckResult, SpamClassifier, Usage, message_text};
+use crate::budget::BudgetExhausted;
+use crate::config::Voting;
+use crate::rules::Rules;
+use crate::state::AppState;
+use anyhow::Context;
+use async_trait::async_trait;
+use futures::future::join_all;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+fn is_budget_exhausted(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| cause.is::<BudgetExhausted>())
+}
+
+pub struct Voter {
+    pub name: String,
+    pub classifier: Box<dyn SpamClassifier>,
+    pub weight: f32,
+    /// Whether the voter is a classifier backend rather than the rules or the local model. A
+    /// majority of the backends must answer for a verdict.
+    pub backend: bool,
+}
+
+/// The rule engine as a voter, abstaining when no rule matches
+pub struct RulesVoter(pub Arc<Rules>);
+
+#[async_trait]
+impl SpamClassifier for RulesVoter {
+    async fn check_spam(&self, req: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+        let verdict = message_text(req.message)
+            .and_then(|text| self.0.check(text))
+            .context("No rule matched")?;
+        Ok(SpamCheckResult::certain(
+            verdict,
+            "Matched a pre-filter rule",
+        ))
+    }
+}
+
+/// The local model as a voter, abstaining until it is trained
+pub struct LocalVoter(pub Arc<AppState>);
+
+#[async_trait]
+impl SpamClassifier for LocalVoter {
+    async fn check_spam(&self, req: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+        let probability = message_text(req.message)
+            .and_then(|text| self.0.local_spam_probability(text))
+            .context("Local model has no opinion")?;
+        Ok(local_verdict(probability))
+    }
+}
+
+/// Verdict of the local model. It only tells spam from ham, not the category.
+pub fn local_verdict(probability: f64) -> SpamCheckResult {
+    let (msg_type, confidence) = if probability >= 0.5 {
+        (MsgType::OtherSpam, probability)
+    } else {
+        (MsgType::NotSpam, 1.0 - probability)
+    };
+    SpamCheckResult {
+        msg_type,
+        confidence: confidence as f32,
+        reason: format!("Local model spam probability {:.3}", probability),
+        usage: Usage::default(),
+        recalled: true,
+    }
+}
+
+/// Asks several classifiers at once and only returns a spam verdict if the voting policy is
+/// met. Voters that fail abstain, but if most backends do, e.g. during an outage, the ensemble
+/// fails rather than letting the few remaining voters decide alone.
+pub struct Ensemble {
+    voters: Vec<Voter>,
+    voting: Voting,
+    weighted_threshold: f32,
+}
+
+impl Ensemble {
+    pub fn new(voters: Vec<Voter>, voting: Voting, weighted_threshold: f32) -> Self {
+        Self {
+            voters,
+            voting,
+            weighted_threshold,
+        }
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for Ensemble {
+    async fn check_spam(&self, req: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+        let results = join_all(self.voters.iter().map(|v| v.classifier.check_spam(req))).await;
+
+        let mut usage = Usage::default();
+        let mut votes = Vec::new();
+        let mut last_error = None;
+        for (voter, res) in self.voters.iter().zip(results) {
+            match res {
+                Ok(res) => {
+                    usage = usage + res.usage;
+                    votes.push((voter, res));
+                }
+                Err(e) => {
+                    tracing::debug!("Voter {} abstained: {:#}", voter.name, e);
+                    // An exhausted budget decides the policy, a voter merely abstaining doesn't
+                    if !last_error.as_ref().is_some_and(is_budget_exhausted) {
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+        if votes.is_empty() {
+            // Keep the cause, e.g. an exhausted budget, for the fail policy
+            let e = last_error.unwrap_or_else(|| anyhow::anyhow!("The ensemble has no voters"));
+            return Err(e.context("No voter of the ensemble answered"));
+        }
+        let backends = self.voters.iter().filter(|v| v.backend).count();
+        let answering = votes.iter().filter(|(v, _)| v.backend).count();
+        if backends > 0
+            && answering * 2 <= backends
+            && let Some(e) = last_error
+        {
+            return Err(e.context(format!(
+                "Only {} of {} classifiers of the ensemble answered",
+                answering, backends
+            )));
+        }
+
+        let spam_votes = votes
+            .iter()
+            .filter(|(_, res)| res.msg_type != MsgType::NotSpam)
+            .collect::<Vec<_>>();
+        let spam_weight = spam_votes.iter().map(|(v, _)| v.weight).sum::<f32>();
+        let total_weight = votes.iter().map(|(v, _)| v.weight).sum::<f32>();
+
+        let summary = votes
+            .iter()
+            .map(|(v, res)| format!("{}: {:?} ({:.2})", v.name, res.msg_type, res.confidence))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if !spam_votes.is_empty() && spam_votes.len() < votes.len() {
+            tracing::warn!(
+                "Voters disagree on message {} in chat {}, candidate for human review: {}",
+                req.message.id,
+                req.message.chat.id,
+                summary
+            );
+        }
+
+        let passed = !spam_votes.is_empty()
+            && match self.voting {
+                Voting::Unanimous => spam_votes.len() == votes.len(),
+                Voting::Majority => spam_votes.len() * 2 > votes.len(),
+                Voting::Weighted => {
+                    total_weight > 0.0 && spam_weight / total_weight >= self.weighted_threshold
+                }
+            };
+
+        if !passed {
+            return Ok(SpamCheckResult {
+                msg_type: MsgType::NotSpam,
+                confidence: if total_weight > 0.0 {
+                    1.0 - spam_weight / total_weight
+                } else {
+                    0.0
+                },
+                reason: format!("Spam vote not met ({})", summary),
+                usage,
+                recalled: false,
+            });
+        }
+
+        // The category backed by the most weight wins, its voters' confidence is averaged
+        let mut by_type = HashMap::<MsgType, (f32, f32)>::new();
+        for (voter, res) in &spam_votes {
+            let (weight, weighted_confidence) = by_type.entry(res.msg_type).or_default();
+            *weight += voter.weight;
+            *weighted_confidence += voter.weight * res.confidence;
+        }
+        let (msg_type, (weight, weighted_confidence)) = by_type
+            .into_iter()
+            .max_by(|a, b| a.1.0.total_cmp(&b.1.0))
+            .unwrap_or((MsgType::OtherSpam, (0.0, 0.0)));
+
+        Ok(SpamCheckResult {
+            msg_type,
+            confidence: if weight > 0.0 {
+                weighted_confidence / weight
+            } else {
+                1.0
+            },
+            reason: format!("Spam vote passed ({})", summary),
+            usage,
+            recalled: false,
+        })
+    }
+
+    fn status(&self) -> String {
+        self.voters
+            .iter()
+            .map(|v| format!("{} {}", v.name, v.classifier.status()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::links::Links;
+    use crate::test_support::text_message;
+
+    struct Fixed(Option<MsgType>);
+
+    #[async_trait]
+    impl SpamClassifier for Fixed {
+        async fn check_spam(&self, _: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+            let msg_type = self.0.context("unavailable")?;
+            Ok(SpamCheckResult::certain(msg_type, ""))
+        }
+    }
+
+    struct Broke;
+
+    #[async_trait]
+    impl SpamClassifier for Broke {
+        async fn check_spam(&self, _: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+            Err(BudgetExhausted {
+                scope: "chat 1".to_string(),
+                limit: "requests per minute",
+            }
+            .into())
+        }
+    }
+
+    fn ensemble(votes: &[(Option<MsgType>, f32)], voting: Voting) -> Ensemble {
+        let voters = votes
+            .iter()
+            .enumerate()
+            .map(|(i, (vote, weight))| Voter {
+                name: i.to_string(),
+                classifier: Box::new(Fixed(*vote)),
+                weight: *weight,
+                backend: true,
+            })
+            .collect();
+        Ensemble::new(voters, voting, 0.6)
+    }
+
+    #[tokio::test]
+    async fn test_voting() {
+        let links = Links::default();
+        let message = text_message(1, 1, "hi");
+        let req = SpamCheckRequest {
+            message: &message,
+            context: &[],
+            media: None,
+            policy: None,
+            links: &links,
+            sender: Default::default(),
+            examples: &[],
+            similar: &[],
+        };
+        let scam = Some(MsgType::Scam);
+        let ham = Some(MsgType::NotSpam);
+
+        let votes = [(scam, 1.0), (scam, 1.0), (ham, 3.0), (None, 5.0)];
+        // 2 of 3 answering voters, but only 2 of 5 answering weight
+        for (voting, expected) in [
+            (Voting::Majority, MsgType::Scam),
+            (Voting::Unanimous, MsgType::NotSpam),
+            (Voting::Weighted, MsgType::NotSpam),
+        ] {
+            let res = ensemble(&votes, voting).check_spam(&req).await.unwrap();
+            assert_eq!(res.msg_type, expected, "{:?}", voting);
+        }
+
+        assert!(
+            ensemble(&[(None, 1.0)], Voting::Majority)
+                .check_spam(&req)
+                .await
+                .is_err()
+        );
+
+        // One backend surviving an outage doesn't decide alone
+        assert!(
+            ensemble(&[(scam, 1.0), (None, 1.0), (None, 1.0)], Voting::Unanimous)
+                .check_spam(&req)
+                .await
+                .is_err()
+        );
+
+        // Without any spam vote, there is nothing to pass
+        let mut lenient = ensemble(&[(ham, 1.0)], Voting::Weighted);
+        lenient.weighted_threshold = 0.0;
+        let res = lenient.check_spam(&req).await.unwrap();
+        assert_eq!(res.msg_type, MsgType::NotSpam);
+        let mut settings = crate::test_support::settings(Default::default());
+        settings.ensemble = serde_json::from_value(serde_json::json!({
+            "voters": [],
+            "voting": "weighted",
+            "weighted_threshold": 0.0,
+        }))
+        .unwrap();
+        assert!(settings.validate().is_err());
+
+        // An exhausted budget is reported over voters that merely abstained
+        let mut exhausted = ensemble(&[(None, 1.0)], Voting::Majority);
+        exhausted.voters.insert(
+            0,
+            Voter {
+                name: "broke".to_string(),
+                classifier: Box::new(Broke),
+                weight: 1.0,
+                backend: true,
+            },
+        );
+        let e = exhausted.check_spam(&req).await.unwrap_err();
+        assert!(is_budget_exhausted(&e));
+    }
+}
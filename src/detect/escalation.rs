@@ -0,0 +1,141 @@
+use super::{MsgType, SpamCheckRequest, SpamCheckResult, SpamClassifier};
+use async_trait::async_trait;
+
+/// Classifies with a cheap model first and asks a stronger one to confirm uncertain and spam
+/// verdicts, so that punitive actions are only taken on the stronger model's word
+pub struct Escalating {
+    first: Box<dyn SpamClassifier>,
+    second: Box<dyn SpamClassifier>,
+    uncertain_below: f32,
+}
+
+impl Escalating {
+    pub fn new(
+        first: Box<dyn SpamClassifier>,
+        second: Box<dyn SpamClassifier>,
+        uncertain_below: f32,
+    ) -> Self {
+        Self {
+            first,
+            second,
+            uncertain_below,
+        }
+    }
+
+    fn needs_escalation(&self, res: &SpamCheckResult) -> bool {
+        res.msg_type != MsgType::NotSpam || res.confidence < self.uncertain_below
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for Escalating {
+    async fn check_spam(&self, req: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+        let first = self.first.check_spam(req).await?;
+        if !self.needs_escalation(&first) {
+            return Ok(first);
+        }
+
+        match self.second.check_spam(req).await {
+            Ok(mut second) => {
+                second.usage = second.usage + first.usage;
+                if second.msg_type != first.msg_type {
+                    tracing::info!(
+                        "Escalation changed verdict from {:?} ({:.2}) to {:?} ({:.2})",
+                        first.msg_type,
+                        first.confidence,
+                        second.msg_type,
+                        second.confidence
+                    );
+                }
+                Ok(second)
+            }
+            // Letting an uncertain message through is harmless, punishing without confirmation
+            // is not, so only spam verdicts need the fail policy
+            Err(e) if first.msg_type == MsgType::NotSpam => {
+                tracing::warn!(
+                    "Escalation classifier failed ({:#}), keeping the first verdict",
+                    e
+                );
+                Ok(first)
+            }
+            Err(e) => Err(e.context("Escalation classifier failed to confirm spam verdict")),
+        }
+    }
+
+    fn status(&self) -> String {
+        format!(
+            "{}, escalation {}",
+            self.first.status(),
+            self.second.status()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::links::Links;
+    use crate::test_support::text_message;
+
+    struct Fixed(Option<SpamCheckResult>);
+
+    #[async_trait]
+    impl SpamClassifier for Fixed {
+        async fn check_spam(&self, _: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+            self.0.clone().ok_or_else(|| anyhow::anyhow!("unavailable"))
+        }
+    }
+
+    fn verdict(msg_type: MsgType, confidence: f32) -> Option<SpamCheckResult> {
+        Some(SpamCheckResult {
+            confidence,
+            ..SpamCheckResult::certain(msg_type, "")
+        })
+    }
+
+    #[tokio::test]
+    async fn test_escalation() {
+        let links = Links::default();
+        let message = text_message(1, 1, "hi");
+        let req = SpamCheckRequest {
+            message: &message,
+            context: &[],
+            media: None,
+            policy: None,
+            links: &links,
+            sender: Default::default(),
+            examples: &[],
+            similar: &[],
+        };
+
+        let escalating =
+            |first, second| Escalating::new(Box::new(Fixed(first)), Box::new(Fixed(second)), 0.8);
+
+        // Confident ham is not escalated
+        let res = escalating(verdict(MsgType::NotSpam, 0.9), None)
+            .check_spam(&req)
+            .await
+            .unwrap();
+        assert_eq!(res.msg_type, MsgType::NotSpam);
+
+        // Spam needs confirmation
+        let res = escalating(verdict(MsgType::Scam, 0.99), verdict(MsgType::NotSpam, 0.9))
+            .check_spam(&req)
+            .await
+            .unwrap();
+        assert_eq!(res.msg_type, MsgType::NotSpam);
+        assert!(
+            escalating(verdict(MsgType::Scam, 0.99), None)
+                .check_spam(&req)
+                .await
+                .is_err()
+        );
+
+        // Uncertain ham falls back to the first verdict
+        let res = escalating(verdict(MsgType::NotSpam, 0.5), None)
+            .check_spam(&req)
+            .await
+            .unwrap();
+        assert_eq!(res.msg_type, MsgType::NotSpam);
+    }
+}
@@ -0,0 +1,205 @@
+use super::{
+    BilledUsage, SpamCheckRequest, SpamCheckResult, SpamClassifier, Usage, build_prompt,
+    parse_response, system_prompt,
+};
+use crate::config::ClassifierSettings;
+use anyhow::Context;
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use gemini_rust::{Model, client::Gemini};
+use schemars::schema_for;
+
+#[derive(Clone)]
+pub struct Agent {
+    client: Gemini,
+}
+
+/// Converts a standard JSON schema to Gemini's simplified schema format
+/// Gemini doesn't support $schema, $defs, or $ref - this function resolves references and removes unsupported fields
+fn convert_to_gemini_schema(mut schema: serde_json::Value) -> serde_json::Value {
+    let defs = schema.get("$defs").cloned();
+
+    if let Some(obj) = schema.as_object_mut() {
+        obj.remove("$schema");
+        obj.remove("$defs");
+    }
+
+    resolve_refs(&mut schema, &defs);
+
+    schema
+}
+
+/// Recursively resolves $ref references in the schema by inlining definitions
+fn resolve_refs(value: &mut serde_json::Value, defs: &Option<serde_json::Value>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            if let Some(ref_path) = map.get("$ref").and_then(|v| v.as_str())
+                && let Some(def_name) = ref_path.strip_prefix("#/$defs/")
+                && let Some(inner_defs) = defs
+                && let Some(definition) = inner_defs.get(def_name)
+            {
+                // Remove the $ref field
+                map.remove("$ref");
+
+                // Merge definition fields into the current object
+                if let Some(def_obj) = definition.as_object() {
+                    for (key, val) in def_obj {
+                        map.insert(key.clone(), val.clone());
+                    }
+                }
+
+                // Continue resolving refs in the merged object
+                resolve_refs(value, defs);
+                return;
+            }
+
+            // If $ref is not directly present, check each individual fields.
+            for val in map.values_mut() {
+                resolve_refs(val, defs);
+            }
+        }
+        serde_json::Value::Array(arr) => {
+            for item in arr.iter_mut() {
+                resolve_refs(item, defs);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl Agent {
+    pub fn new(settings: &ClassifierSettings, gemini_api_key: &str) -> anyhow::Result<Self> {
+        let api_key = settings.api_key.as_deref().unwrap_or(gemini_api_key);
+        let model = match settings.model.as_deref() {
+            // The API expects custom model names with the `models/` prefix
+            Some(name) if name.starts_with("models/") => Model::Custom(name.to_string()),
+            Some(name) => Model::Custom(format!("models/{}", name)),
+            None => Model::Gemini3Flash,
+        };
+        let client = match &settings.base_url {
+            Some(url) => Gemini::with_model_and_base_url(api_key, model, url.parse()?)?,
+            None => Gemini::with_model(api_key, model)?,
+        };
+        Ok(Self { client })
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for Agent {
+    async fn check_spam(&self, req: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+        // Convert standard JSON schema to Gemini's format
+        let standard_schema = schema_for!(SpamCheckResult);
+        let gemini_schema =
+            convert_to_gemini_schema(serde_json::to_value(standard_schema).unwrap());
+
+        let prompt = build_prompt(req, req.media.is_some());
+
+        let mut builder = self
+            .client
+            .generate_content()
+            .with_response_mime_type("application/json")
+            .with_response_schema(gemini_schema)
+            .with_system_prompt(system_prompt(req.policy));
+
+        // Gemini recommends placing media before the text referring to it
+        if let Some(media) = req.media {
+            builder = builder.with_inline_data(BASE64.encode(&media.data), &media.mime_type);
+        }
+
+        let response = builder.with_user_message(&prompt).execute().await?;
+
+        let usage = response
+            .usage_metadata
+            .as_ref()
+            .map_or_else(Usage::default, |usage| {
+                let count = |c: Option<i32>| c.unwrap_or_default().max(0) as u64;
+                Usage {
+                    prompt_tokens: count(usage.prompt_token_count),
+                    completion_tokens: count(usage.candidates_token_count)
+                        + count(usage.thoughts_token_count),
+                }
+            });
+        let mut result = parse_response("Gemini", &response.text()).context(BilledUsage(usage))?;
+        result.usage = usage;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ResilienceSettings;
+    use crate::detect::{MsgType, Resilient, SenderProfile};
+    use crate::links::Links;
+    use crate::test_support::{MockGemini, Reply, text_message};
+    use std::time::Duration;
+
+    async fn check(mock: &MockGemini) -> anyhow::Result<SpamCheckResult> {
+        let classifier = Resilient::new(
+            Box::new(Agent::new(&mock.settings(), "").unwrap()),
+            ResilienceSettings {
+                timeout_secs: 1,
+                max_retries: 1,
+                backoff_base_ms: 1,
+                backoff_max_ms: 1,
+                ..Default::default()
+            },
+        );
+        let message = text_message(1, 1, "Free crypto, DM me");
+        classifier
+            .check_spam(&SpamCheckRequest {
+                message: &message,
+                context: &[],
+                media: None,
+                policy: Some("No crypto talk"),
+                links: &Links::default(),
+                sender: SenderProfile::default(),
+                examples: &[],
+                similar: &[],
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_check_spam_against_mock_server() {
+        let mock = MockGemini::start(vec![Reply::Verdict(MsgType::Scam, 0.9)]).await;
+        let res = check(&mock).await.unwrap();
+        assert_eq!(res.msg_type, MsgType::Scam);
+        assert_eq!(res.usage.prompt_tokens, 100);
+        assert_eq!(res.usage.completion_tokens, 10);
+        let request = mock.requests()[0].to_string();
+        assert!(request.contains("Free crypto, DM me"));
+        assert!(request.contains("No crypto talk"));
+
+        // Garbage output is an error, but not worth retrying
+        let mock = MockGemini::start(vec![Reply::Text("definitely spam".to_string())]).await;
+        assert!(check(&mock).await.is_err());
+        assert_eq!(mock.requests().len(), 1);
+
+        // Server errors are retried
+        let mock = MockGemini::start(vec![
+            Reply::Status(500),
+            Reply::Verdict(MsgType::NotSpam, 0.8),
+        ])
+        .await;
+        assert_eq!(check(&mock).await.unwrap().msg_type, MsgType::NotSpam);
+        assert_eq!(mock.requests().len(), 2);
+
+        let mock = MockGemini::start(vec![Reply::Status(429)]).await;
+        assert!(check(&mock).await.is_err());
+        assert_eq!(mock.requests().len(), 2);
+
+        // Client errors are not
+        let mock = MockGemini::start(vec![Reply::Status(400)]).await;
+        assert!(check(&mock).await.is_err());
+        assert_eq!(mock.requests().len(), 1);
+
+        // Slow responses time out
+        let mock = MockGemini::start(vec![Reply::Delayed(
+            Duration::from_secs(3),
+            Box::new(Reply::Verdict(MsgType::NotSpam, 1.0)),
+        )])
+        .await;
+        assert!(check(&mock).await.is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use super::{BilledUsage, SpamCheckRequest, SpamCheckResult, SpamClassifier};
+use crate::config::BudgetSettings;
+use crate::state::AppState;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Counts every request to a backend against the budgets of the chat, refusing it once they
+/// are exhausted. Retries, escalations and ensemble voters are each charged, and their tokens
+/// recorded for the usage report whether or not the response could be used.
+pub struct Metered {
+    inner: Box<dyn SpamClassifier>,
+    state: Arc<AppState>,
+    budget: BudgetSettings,
+    usage_retention_days: i64,
+}
+
+impl Metered {
+    pub fn new(
+        inner: Box<dyn SpamClassifier>,
+        state: Arc<AppState>,
+        budget: BudgetSettings,
+        usage_retention_days: i64,
+    ) -> Self {
+        Self {
+            inner,
+            state,
+            budget,
+            usage_retention_days,
+        }
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for Metered {
+    async fn check_spam(&self, req: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+        let chat_id = req.message.chat.id;
+        self.state.try_spend_request(chat_id, &self.budget)?;
+
+        let res = self.inner.check_spam(req).await;
+        // Unusable responses are billed too
+        let usage = match &res {
+            Ok(res) => Some(res.usage),
+            Err(e) => e.downcast_ref::<BilledUsage>().map(|billed| billed.0),
+        };
+        if let Some(usage) = usage {
+            self.state.spend_tokens(chat_id, usage.total());
+            self.state
+                .record_usage(chat_id, usage, self.usage_retention_days);
+        }
+        res
+    }
+
+    fn status(&self) -> String {
+        self.inner.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::BudgetExhausted;
+    use crate::detect::{Escalating, MsgType, Usage};
+    use crate::links::Links;
+    use crate::test_support::text_message;
+    use anyhow::Context;
+
+    struct Spam;
+
+    #[async_trait]
+    impl SpamClassifier for Spam {
+        async fn check_spam(&self, _: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+            Ok(SpamCheckResult::certain(MsgType::Scam, ""))
+        }
+    }
+
+    struct Garbled;
+
+    #[async_trait]
+    impl SpamClassifier for Garbled {
+        async fn check_spam(&self, _: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+            Err(anyhow::anyhow!("not JSON")).context(BilledUsage(Usage {
+                prompt_tokens: 100,
+                completion_tokens: 5,
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_every_backend_call_is_charged() {
+        let state = Arc::new(AppState::new());
+        let mut budget = BudgetSettings::default();
+        budget.per_chat.requests_per_minute = Some(3);
+        let metered = || {
+            Box::new(Metered::new(
+                Box::new(Spam),
+                state.clone(),
+                budget.clone(),
+                90,
+            ))
+        };
+        let classifier = Escalating::new(metered(), metered(), 0.8);
+
+        let links = Links::default();
+        let message = text_message(1, 1, "hi");
+        let req = SpamCheckRequest {
+            message: &message,
+            context: &[],
+            media: None,
+            policy: None,
+            links: &links,
+            sender: Default::default(),
+            examples: &[],
+            similar: &[],
+        };
+
+        // Spam is escalated, so each check takes two requests
+        classifier.check_spam(&req).await.unwrap();
+        let e = classifier.check_spam(&req).await.unwrap_err();
+        assert!(e.chain().any(|cause| cause.is::<BudgetExhausted>()));
+    }
+
+    #[tokio::test]
+    async fn test_unusable_responses_are_billed() {
+        let state = Arc::new(AppState::new());
+        let classifier = Metered::new(Box::new(Garbled), state.clone(), Default::default(), 90);
+
+        let links = Links::default();
+        let message = text_message(1, 1, "hi");
+        let req = SpamCheckRequest {
+            message: &message,
+            context: &[],
+            media: None,
+            policy: None,
+            links: &links,
+            sender: Default::default(),
+            examples: &[],
+            similar: &[],
+        };
+        classifier.check_spam(&req).await.unwrap_err();
+
+        let report = state.usage_report(message.chat.id, 1);
+        assert_eq!(report[0].1.requests, 1);
+        assert_eq!(report[0].1.prompt_tokens, 100);
+        assert_eq!(report[0].1.completion_tokens, 5);
+    }
+}
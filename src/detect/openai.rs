@@ -0,0 +1,256 @@
+use super::{
+    BilledUsage, SpamCheckRequest, SpamCheckResult, SpamClassifier, Usage, build_prompt,
+    parse_response, system_prompt,
+};
+use crate::config::ClassifierSettings;
+use anyhow::Context;
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use reqwest::Url;
+use schemars::schema_for;
+use serde::Deserialize;
+use serde_json::json;
+
+/// Classifier talking to an OpenAI-compatible chat completions endpoint
+/// (OpenAI, llama.cpp server, vLLM, ...)
+#[derive(Clone)]
+pub struct OpenAiAgent {
+    client: reqwest::Client,
+    endpoint: Url,
+    api_key: Option<String>,
+    model: String,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionResponse {
+    choices: Vec<Choice>,
+    /// Not reported by every server
+    #[serde(default)]
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Deserialize)]
+struct CompletionUsage {
+    #[serde(default)]
+    prompt_tokens: u64,
+    #[serde(default)]
+    completion_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    message: ChoiceMessage,
+}
+
+#[derive(Deserialize)]
+struct ChoiceMessage {
+    content: Option<String>,
+}
+
+impl OpenAiAgent {
+    pub fn new(settings: &ClassifierSettings) -> anyhow::Result<Self> {
+        let base_url = settings
+            .base_url
+            .as_deref()
+            .context("base_url is required for the OpenAI-compatible backend")?;
+        let model = settings
+            .model
+            .clone()
+            .context("model is required for the OpenAI-compatible backend")?;
+
+        // Make sure the base URL is treated as a directory when joining
+        let mut base_url: Url = base_url.parse()?;
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            endpoint: base_url.join("chat/completions")?,
+            api_key: settings.api_key.clone(),
+            model,
+        })
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for OpenAiAgent {
+    async fn check_spam(&self, req: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+        let schema = serde_json::to_value(schema_for!(SpamCheckResult))?;
+        // Most OpenAI-compatible servers only accept images, other media is described in text
+        // only and marked as withheld
+        let image = req.media.filter(|m| m.mime_type.starts_with("image/"));
+        let prompt = build_prompt(req, image.is_some());
+
+        let user_content = match image {
+            Some(media) => json!([
+                { "type": "text", "text": prompt },
+                {
+                    "type": "image_url",
+                    "image_url": {
+                        "url": format!("data:{};base64,{}", media.mime_type, BASE64.encode(&media.data)),
+                    },
+                },
+            ]),
+            None => json!(prompt),
+        };
+
+        let body = json!({
+            "model": self.model,
+            "messages": [
+                { "role": "system", "content": system_prompt(req.policy) },
+                { "role": "user", "content": user_content },
+            ],
+            "response_format": {
+                "type": "json_schema",
+                "json_schema": { "name": "spam_check_result", "schema": schema },
+            },
+        });
+
+        let mut request = self.client.post(self.endpoint.clone()).json(&body);
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+
+        let response: ChatCompletionResponse =
+            request.send().await?.error_for_status()?.json().await?;
+
+        let usage = response.usage.map_or_else(Usage::default, |u| Usage {
+            prompt_tokens: u.prompt_tokens,
+            completion_tokens: u.completion_tokens,
+        });
+        let response_text = response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|c| c.message.content)
+            .unwrap_or_default();
+
+        let mut result =
+            parse_response("OpenAI-compatible", &response_text).context(BilledUsage(usage))?;
+        result.usage = usage;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detect::{MsgType, SenderProfile};
+    use crate::links::Links;
+    use crate::media::Media;
+    use crate::test_support::{MockGemini, Reply, text_message};
+
+    async fn check(mock: &MockGemini, media: Option<&Media>) -> anyhow::Result<SpamCheckResult> {
+        let message = match media {
+            None => text_message(1, 1, "Free crypto, DM me"),
+            Some(media) => {
+                let file = json!({
+                    "file_id": "file", "file_unique_id": "file", "file_size": 3,
+                    "width": 1, "height": 1, "duration": 1, "mime_type": media.mime_type,
+                });
+                let attachment = if media.mime_type.starts_with("image/") {
+                    json!({ "photo": [file] })
+                } else {
+                    json!({ "video": file })
+                };
+                let mut message = json!({
+                    "message_id": 1,
+                    "date": 0,
+                    "chat": {"id": -100, "type": "supergroup", "title": "Test"},
+                    "from": {"id": 1, "is_bot": false, "first_name": "Test"},
+                    "caption": "Free crypto, DM me",
+                });
+                message
+                    .as_object_mut()
+                    .unwrap()
+                    .extend(attachment.as_object().unwrap().clone());
+                serde_json::from_value(message).unwrap()
+            }
+        };
+
+        let agent = OpenAiAgent::new(&mock.settings()).unwrap();
+        agent
+            .check_spam(&SpamCheckRequest {
+                message: &message,
+                context: &[],
+                media,
+                policy: Some("No crypto talk"),
+                links: &Links::default(),
+                sender: SenderProfile::default(),
+                examples: &[],
+                similar: &[],
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_check_spam_against_mock_server() {
+        let mock =
+            MockGemini::start_chat_completions(vec![Reply::Verdict(MsgType::Scam, 0.9)]).await;
+        let res = check(&mock, None).await.unwrap();
+        assert_eq!(res.msg_type, MsgType::Scam);
+        assert_eq!(res.usage.prompt_tokens, 100);
+        assert_eq!(res.usage.completion_tokens, 10);
+
+        let request = &mock.requests()[0];
+        assert_eq!(request["model"], "test-model");
+        assert_eq!(request["messages"][0]["role"], "system");
+        assert!(
+            request["messages"][0]["content"]
+                .as_str()
+                .unwrap()
+                .contains("No crypto talk")
+        );
+        assert_eq!(request["messages"][1]["role"], "user");
+        let content = request["messages"][1]["content"].as_str().unwrap();
+        assert!(content.contains("Free crypto, DM me"));
+        assert_eq!(request["response_format"]["type"], "json_schema");
+
+        // Images are sent as data URLs next to the prompt
+        let image = Media {
+            mime_type: "image/png".to_string(),
+            data: b"png".to_vec(),
+        };
+        check(&mock, Some(&image)).await.unwrap();
+        let parts = &mock.requests()[1]["messages"][1]["content"];
+        assert_eq!(parts[0]["type"], "text");
+        assert!(parts[0]["text"].as_str().unwrap().contains("Free crypto"));
+        assert_eq!(parts[1]["type"], "image_url");
+        assert_eq!(parts[1]["image_url"]["url"], "data:image/png;base64,cG5n");
+        assert!(
+            !parts[0]["text"]
+                .as_str()
+                .unwrap()
+                .contains("media_withheld")
+        );
+
+        // Other media is left out, and the prompt says so
+        let video = Media {
+            mime_type: "video/mp4".to_string(),
+            data: b"mp4".to_vec(),
+        };
+        check(&mock, Some(&video)).await.unwrap();
+        let content = mock.requests()[2]["messages"][1]["content"].clone();
+        let prompt: serde_json::Value = serde_json::from_str(content.as_str().unwrap()).unwrap();
+        assert_eq!(prompt["message"]["media"], "video");
+        assert_eq!(prompt["message"]["media_withheld"], true);
+
+        // Garbage output is an error, but the tokens were still billed
+        let mock =
+            MockGemini::start_chat_completions(vec![Reply::Text("definitely spam".to_string())])
+                .await;
+        let err = check(&mock, None).await.unwrap_err();
+        let billed = err.downcast_ref::<BilledUsage>().unwrap();
+        assert_eq!(billed.0.total(), 110);
+
+        // Error statuses surface as errors
+        let mock = MockGemini::start_chat_completions(vec![Reply::Status(503)]).await;
+        let err = check(&mock, None).await.unwrap_err();
+        let status = err
+            .downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.status());
+        assert_eq!(status.map(|s| s.as_u16()), Some(503));
+        assert_eq!(mock.requests().len(), 1);
+    }
+}
@@ -0,0 +1,276 @@
+use super::{SpamCheckRequest, SpamCheckResult, SpamClassifier};
+use crate::budget::BudgetExhausted;
+use crate::config::ResilienceSettings;
+use async_trait::async_trait;
+use gemini_rust::ClientError;
+use rand::Rng;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::time::{self, error::Elapsed};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests go through
+    Closed,
+    /// The backend is considered down, requests fail immediately until the given instant
+    Open(Instant),
+    /// The cooldown is over, the next request decides whether to close or reopen the circuit
+    HalfOpen,
+}
+
+/// Circuit breaker pausing requests to a backend after consecutive transient failures
+pub struct CircuitBreaker {
+    inner: Mutex<Circuit>,
+    failure_threshold: u32,
+    cooldown: Duration,
+    /// A probe not finished after this long is considered lost, e.g. because its request was
+    /// cancelled
+    probe_timeout: Duration,
+}
+
+struct Circuit {
+    state: CircuitState,
+    consecutive_failures: u32,
+    /// When the single request allowed through a half-open circuit was sent
+    probe_started: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: &ResilienceSettings) -> Self {
+        Self {
+            inner: Mutex::new(Circuit {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                probe_started: None,
+            }),
+            failure_threshold: settings.circuit_failure_threshold,
+            cooldown: Duration::from_secs(settings.circuit_cooldown_secs),
+            probe_timeout: Duration::from_secs(settings.timeout_secs),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Check whether a request may be sent. Once the cooldown is over, a single probe is let
+    /// through and everything else keeps failing fast until it returns.
+    pub fn allow_request(&self) -> bool {
+        let mut circuit = self.inner.lock().unwrap();
+        let now = Instant::now();
+        match circuit.state {
+            CircuitState::Closed => true,
+            CircuitState::Open(until) if now < until => false,
+            CircuitState::Open(_) => {
+                tracing::info!("Circuit half-open, probing the backend");
+                circuit.state = CircuitState::HalfOpen;
+                circuit.probe_started = Some(now);
+                true
+            }
+            CircuitState::HalfOpen => {
+                let probing = circuit
+                    .probe_started
+                    .is_some_and(|started| now.duration_since(started) < self.probe_timeout);
+                if !probing {
+                    circuit.probe_started = Some(now);
+                }
+                !probing
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut circuit = self.inner.lock().unwrap();
+        if circuit.state != CircuitState::Closed {
+            tracing::info!("Circuit closed, backend recovered");
+        }
+        circuit.state = CircuitState::Closed;
+        circuit.consecutive_failures = 0;
+        circuit.probe_started = None;
+    }
+
+    pub fn record_failure(&self) {
+        let mut circuit = self.inner.lock().unwrap();
+        circuit.consecutive_failures += 1;
+
+        let trip = circuit.state == CircuitState::HalfOpen
+            || circuit.consecutive_failures >= self.failure_threshold;
+        if trip {
+            tracing::warn!(
+                "Circuit open after {} consecutive failures, pausing requests for {:?}",
+                circuit.consecutive_failures,
+                self.cooldown
+            );
+            circuit.state = CircuitState::Open(Instant::now() + self.cooldown);
+            circuit.probe_started = None;
+        }
+    }
+}
+
+/// Wraps a classifier with a timeout, bounded concurrency, retries with jittered backoff and a
+/// circuit breaker
+pub struct Resilient {
+    inner: Box<dyn SpamClassifier>,
+    settings: ResilienceSettings,
+    in_flight: Semaphore,
+    circuit: CircuitBreaker,
+}
+
+/// Whether a failure is worth retrying and counts against the backend health
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        if cause.is::<Elapsed>() {
+            return true;
+        }
+        if let Some(ClientError::BadResponse { code, .. }) = cause.downcast_ref::<ClientError>() {
+            return *code == 429 || *code >= 500;
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return match e.status() {
+                Some(status) => status.as_u16() == 429 || status.is_server_error(),
+                None => e.is_timeout() || e.is_connect() || e.is_request(),
+            };
+        }
+        false
+    })
+}
+
+impl Resilient {
+    pub fn new(inner: Box<dyn SpamClassifier>, settings: ResilienceSettings) -> Self {
+        Self {
+            inner,
+            in_flight: Semaphore::new(settings.max_in_flight),
+            circuit: CircuitBreaker::new(&settings),
+            settings,
+        }
+    }
+
+    pub fn circuit_state(&self) -> CircuitState {
+        self.circuit.state()
+    }
+
+    /// Full jitter backoff: a random delay up to `base * 2^attempt`, capped
+    fn backoff(&self, attempt: u32) -> Duration {
+        let max = self
+            .settings
+            .backoff_base_ms
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.settings.backoff_max_ms);
+        Duration::from_millis(rand::rng().random_range(0..=max))
+    }
+
+    async fn attempt(&self, req: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+        // The timeout also covers waiting for a free slot, so handlers don't pile up
+        time::timeout(Duration::from_secs(self.settings.timeout_secs), async {
+            let _permit = self.in_flight.acquire().await?;
+            self.inner.check_spam(req).await
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for Resilient {
+    async fn check_spam(&self, req: &SpamCheckRequest<'_>) -> anyhow::Result<SpamCheckResult> {
+        let mut attempt = 0;
+        loop {
+            if !self.circuit.allow_request() {
+                anyhow::bail!("Classifier circuit is open, backend considered unavailable");
+            }
+
+            match self.attempt(req).await {
+                Ok(res) => {
+                    self.circuit.record_success();
+                    return Ok(res);
+                }
+                Err(e) if is_transient(&e) => {
+                    self.circuit.record_failure();
+                    if attempt >= self.settings.max_retries {
+                        return Err(e);
+                    }
+
+                    let delay = self.backoff(attempt);
+                    tracing::warn!(
+                        "Classifier request failed ({:#}), retrying in {:?}",
+                        e,
+                        delay
+                    );
+                    time::sleep(delay).await;
+                    attempt += 1;
+                }
+                // Nothing was sent, the backend health is unknown
+                Err(e) if e.is::<BudgetExhausted>() => return Err(e),
+                // The backend answered, just not with something usable
+                Err(e) => {
+                    self.circuit.record_success();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn status(&self) -> String {
+        match self.circuit_state() {
+            CircuitState::Closed => "available".to_string(),
+            CircuitState::HalfOpen => "recovering (circuit half-open)".to_string(),
+            CircuitState::Open(until) => format!(
+                "unavailable (circuit open, retrying in {}s)",
+                until.saturating_duration_since(Instant::now()).as_secs()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(cooldown_secs: u64) -> CircuitBreaker {
+        CircuitBreaker::new(&ResilienceSettings {
+            circuit_failure_threshold: 3,
+            circuit_cooldown_secs: cooldown_secs,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_circuit_trips_and_cools_down() {
+        let circuit = breaker(60);
+        circuit.record_failure();
+        circuit.record_failure();
+        assert!(circuit.allow_request());
+        // Successes reset the count
+        circuit.record_success();
+        circuit.record_failure();
+        circuit.record_failure();
+        assert_eq!(circuit.state(), CircuitState::Closed);
+
+        circuit.record_failure();
+        assert!(matches!(circuit.state(), CircuitState::Open(_)));
+        assert!(!circuit.allow_request());
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let circuit = breaker(0);
+        for _ in 0..3 {
+            circuit.record_failure();
+        }
+
+        // Only one probe goes through once the cooldown is over
+        assert!(circuit.allow_request());
+        assert_eq!(circuit.state(), CircuitState::HalfOpen);
+        assert!(!circuit.allow_request());
+
+        // A failed probe reopens the circuit right away
+        circuit.record_failure();
+        assert!(matches!(circuit.state(), CircuitState::Open(_)));
+
+        assert!(circuit.allow_request());
+        circuit.record_success();
+        assert_eq!(circuit.state(), CircuitState::Closed);
+        assert!(circuit.allow_request());
+        assert!(circuit.allow_request());
+    }
+}
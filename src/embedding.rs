@@ -0,0 +1,458 @@
+use crate::config::{BudgetSettings, EmbeddingBackend, EmbeddingSettings, Settings};
+use crate::detect::{CircuitBreaker, MsgType, Usage};
+use crate::examples::LabeledExample;
+use crate::fingerprint::fnv1a;
+use crate::normalize;
+use crate::state::AppState;
+use anyhow::Context;
+use async_trait::async_trait;
+use gemini_rust::{Model, TaskType, client::Gemini};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::types::ChatId;
+
+const DEFAULT_GEMINI_MODEL: &str = "models/gemini-embedding-001";
+
+/// Dimensions of the vectors of the local embedder
+const LOCAL_DIMENSIONS: usize = 512;
+
+/// Turns a text into a vector placing texts of similar meaning close together
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// Embed a text on behalf of a chat, whose budget pays for remote requests
+    async fn embed(&self, chat_id: ChatId, text: &str) -> anyhow::Result<Vec<f32>>;
+}
+
+/// Gemini embedding endpoint
+pub struct GeminiEmbedder {
+    client: Gemini,
+    timeout: Duration,
+}
+
+impl GeminiEmbedder {
+    pub fn new(
+        settings: &EmbeddingSettings,
+        gemini_api_key: &str,
+        timeout: Duration,
+    ) -> anyhow::Result<Self> {
+        let api_key = settings.api_key.as_deref().unwrap_or(gemini_api_key);
+        let model = match settings.model.as_deref() {
+            Some(name) if name.starts_with("models/") => Model::Custom(name.to_string()),
+            Some(name) => Model::Custom(format!("models/{}", name)),
+            None => Model::Custom(DEFAULT_GEMINI_MODEL.to_string()),
+        };
+        let client = match &settings.base_url {
+            Some(url) => Gemini::with_model_and_base_url(api_key, model, url.parse()?)?,
+            None => Gemini::with_model(api_key, model)?,
+        };
+        Ok(Self { client, timeout })
+    }
+}
+
+#[async_trait]
+impl Embedder for GeminiEmbedder {
+    async fn embed(&self, _: ChatId, text: &str) -> anyhow::Result<Vec<f32>> {
+        let response = tokio::time::timeout(
+            self.timeout,
+            self.client
+                .embed_content()
+                .with_text(text)
+                .with_task_type(TaskType::SemanticSimilarity)
+                .execute(),
+        )
+        .await
+        .context("Gemini embedding request timed out")?
+        .context("Gemini embedding request failed")?;
+        Ok(response.embedding.values)
+    }
+}
+
+/// Counts requests to a remote embedder against the budgets and usage of the chat, and pauses
+/// them while the endpoint keeps failing
+pub struct Guarded {
+    inner: Box<dyn Embedder>,
+    state: Arc<AppState>,
+    budget: BudgetSettings,
+    usage_retention_days: i64,
+    circuit: CircuitBreaker,
+}
+
+impl Guarded {
+    pub fn new(inner: Box<dyn Embedder>, state: Arc<AppState>, settings: &Settings) -> Self {
+        Self {
+            inner,
+            state,
+            budget: settings.budget.clone(),
+            usage_retention_days: settings.usage_retention_days,
+            circuit: CircuitBreaker::new(&settings.resilience),
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for Guarded {
+    async fn embed(&self, chat_id: ChatId, text: &str) -> anyhow::Result<Vec<f32>> {
+        if !self.circuit.allow_request() {
+            anyhow::bail!("Embedding endpoint unavailable, circuit open");
+        }
+        self.state.try_spend_request(chat_id, &self.budget)?;
+
+        let res = self.inner.embed(chat_id, text).await;
+        if res.is_err() {
+            self.circuit.record_failure();
+            return res;
+        }
+        self.circuit.record_success();
+
+        // The endpoint doesn't report tokens, estimate them at four characters each
+        let usage = Usage {
+            prompt_tokens: text.chars().count().div_ceil(4) as u64,
+            completion_tokens: 0,
+        };
+        self.state.spend_tokens(chat_id, usage.total());
+        self.state
+            .record_usage(chat_id, usage, self.usage_retention_days);
+        res
+    }
+}
+
+/// Hashed character trigrams and words of the skeleton. It knows nothing about meaning, but
+/// catches reworded copies for free and without network access.
+pub struct LocalEmbedder;
+
+#[async_trait]
+impl Embedder for LocalEmbedder {
+    async fn embed(&self, _: ChatId, text: &str) -> anyhow::Result<Vec<f32>> {
+        let skeleton = normalize::skeleton(text);
+        let chars = skeleton.chars().collect::<Vec<_>>();
+        let features = chars
+            .windows(3)
+            .map(|w| w.iter().collect::<String>())
+            .chain(skeleton.split_whitespace().map(|w| format!("#{}", w)));
+
+        let mut vector = vec![0.0; LOCAL_DIMENSIONS];
+        for feature in features {
+            let hash = fnv1a(&feature);
+            let sign = if hash >> 63 == 1 { 1.0 } else { -1.0 };
+            vector[(hash % LOCAL_DIMENSIONS as u64) as usize] += sign;
+        }
+        Ok(vector)
+    }
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 { 0.0 } else { dot / norms }
+}
+
+/// A labeled message close to the one being checked
+#[derive(Debug, Clone, PartialEq)]
+pub struct Neighbour {
+    pub text: String,
+    pub label: MsgType,
+    /// Cosine similarity to the message being checked
+    pub similarity: f32,
+}
+
+/// An embedded labeled message of a chat
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedMessage {
+    pub text: String,
+    pub label: MsgType,
+    pub vector: Vec<f32>,
+    /// Embedder the vector comes from, vectors of different embedders aren't comparable
+    pub embedder: String,
+}
+
+/// Vector index of the messages labeled by the moderators of each chat, searched by brute
+/// force. The vectors are kept in the state so that they survive restarts.
+pub struct SemanticIndex {
+    /// `None` if embeddings are not configured
+    embedder: Option<Box<dyn Embedder>>,
+    /// Name of the embedder, e.g. `gemini:models/gemini-embedding-001`
+    embedder_name: String,
+    state: Arc<AppState>,
+    settings: EmbeddingSettings,
+}
+
+impl SemanticIndex {
+    pub fn new(
+        embedder: Option<Box<dyn Embedder>>,
+        embedder_name: impl Into<String>,
+        state: Arc<AppState>,
+        settings: EmbeddingSettings,
+    ) -> Self {
+        Self {
+            embedder,
+            embedder_name: embedder_name.into(),
+            state,
+            settings,
+        }
+    }
+
+    /// Build the index with the configured embedder, if any. Remote embedders are metered and
+    /// guarded like the classifier backends.
+    pub fn from_settings(settings: &Settings, state: Arc<AppState>) -> anyhow::Result<Self> {
+        let Some(embedding) = &settings.embedding else {
+            return Ok(Self::new(None, "", state, Default::default()));
+        };
+        let (embedder, name): (Box<dyn Embedder>, _) = match embedding.backend {
+            EmbeddingBackend::Gemini => (
+                Box::new(Guarded::new(
+                    Box::new(GeminiEmbedder::new(
+                        embedding,
+                        &settings.gemini_api_key,
+                        Duration::from_secs(settings.resilience.timeout_secs),
+                    )?),
+                    state.clone(),
+                    settings,
+                )),
+                format!(
+                    "gemini:{}",
+                    embedding.model.as_deref().unwrap_or(DEFAULT_GEMINI_MODEL)
+                ),
+            ),
+            EmbeddingBackend::Local => (Box::new(LocalEmbedder), "local".to_string()),
+        };
+        Ok(Self::new(Some(embedder), name, state, embedding.clone()))
+    }
+
+    async fn embed(&self, chat_id: ChatId, text: &str) -> Option<Vec<f32>> {
+        let embedder = self.embedder.as_ref()?;
+        match embedder.embed(chat_id, text).await {
+            Ok(vector) => Some(vector),
+            Err(e) => {
+                tracing::warn!("Failed to embed message: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Add a message labeled by the moderators of a chat. A message indexed before takes the
+    /// new label.
+    pub async fn add(&self, chat_id: ChatId, text: &str, label: MsgType) {
+        let Some(vector) = self.embed(chat_id, text).await else {
+            return;
+        };
+        let mut entries = self.state.semantic_index.entry(chat_id.0).or_default();
+        entries.retain(|e| e.text != text && e.embedder == self.embedder_name);
+        entries.push_back(IndexedMessage {
+            text: text.to_string(),
+            label,
+            vector,
+            embedder: self.embedder_name.clone(),
+        });
+        while entries.len() > self.settings.capacity {
+            entries.pop_front();
+        }
+    }
+
+    /// Index the labeled examples kept in the state and the chats they belong to that aren't
+    /// indexed yet, e.g. after embeddings were enabled
+    pub async fn seed(&self, examples: impl IntoIterator<Item = (ChatId, LabeledExample)>) {
+        if self.embedder.is_none() {
+            return;
+        }
+        let mut count = 0;
+        for (chat_id, example) in examples {
+            let indexed = self
+                .state
+                .semantic_index
+                .get(&chat_id.0)
+                .is_some_and(|entries| {
+                    entries
+                        .iter()
+                        .any(|e| e.text == example.text && e.embedder == self.embedder_name)
+                });
+            if !indexed {
+                self.add(chat_id, &example.text, example.label).await;
+                count += 1;
+            }
+        }
+        tracing::info!("Indexed {} labeled examples for semantic search", count);
+    }
+
+    /// The labeled messages of a chat most similar to a text, most similar first, leaving out
+    /// those too far away to tell anything
+    pub async fn nearest(&self, chat_id: ChatId, text: &str) -> Vec<Neighbour> {
+        // Nothing to compare with isn't worth an embedding request
+        let comparable = |e: &IndexedMessage| e.embedder == self.embedder_name;
+        let empty = !self
+            .state
+            .semantic_index
+            .get(&chat_id.0)
+            .is_some_and(|entries| entries.iter().any(comparable));
+        if empty {
+            return Vec::new();
+        }
+        let Some(vector) = self.embed(chat_id, text).await else {
+            return Vec::new();
+        };
+
+        let Some(entries) = self.state.semantic_index.get(&chat_id.0) else {
+            return Vec::new();
+        };
+        let mut neighbours = entries
+            .iter()
+            .filter(|e| comparable(e))
+            .map(|e| Neighbour {
+                text: e.text.clone(),
+                label: e.label,
+                similarity: cosine(&vector, &e.vector),
+            })
+            .filter(|n| n.similarity >= self.settings.evidence_above)
+            .collect::<Vec<_>>();
+        neighbours.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        neighbours.truncate(self.settings.neighbours);
+        neighbours
+    }
+
+    /// The nearest neighbour if it is confirmed spam close enough to call the message a
+    /// paraphrase of it
+    pub fn paraphrased_spam<'a>(&self, neighbours: &'a [Neighbour]) -> Option<&'a Neighbour> {
+        neighbours
+            .first()
+            .filter(|n| n.label != MsgType::NotSpam && n.similarity >= self.settings.spam_above)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_nearest_neighbours() {
+        let chat_id = ChatId(-100);
+        let state = Arc::new(AppState::new());
+        let index = SemanticIndex::new(
+            Some(Box::new(LocalEmbedder)),
+            "local",
+            state.clone(),
+            EmbeddingSettings {
+                evidence_above: 0.5,
+                spam_above: 0.8,
+                neighbours: 2,
+                ..Default::default()
+            },
+        );
+        index
+            .add(
+                chat_id,
+                "I earn 3000 dollars a week trading crypto with Mrs. Anna, write to her now",
+                MsgType::Scam,
+            )
+            .await;
+        index
+            .add(
+                chat_id,
+                "Does anyone know a good Rust book for beginners?",
+                MsgType::NotSpam,
+            )
+            .await;
+
+        let neighbours = index
+            .nearest(
+                chat_id,
+                "I earn 5000 dollars every week trading crypto with Mrs. Anna, write her",
+            )
+            .await;
+        assert_eq!(neighbours.len(), 1);
+        assert_eq!(
+            index.paraphrased_spam(&neighbours).unwrap().label,
+            MsgType::Scam
+        );
+
+        let neighbours = index
+            .nearest(chat_id, "Any good books on Rust for a beginner?")
+            .await;
+        assert_eq!(neighbours[0].label, MsgType::NotSpam);
+        assert!(index.paraphrased_spam(&neighbours).is_none());
+
+        // Relabeling replaces the entry
+        index
+            .add(
+                chat_id,
+                "Does anyone know a good Rust book for beginners?",
+                MsgType::OtherSpam,
+            )
+            .await;
+        assert_eq!(state.semantic_index.get(&chat_id.0).unwrap().len(), 2);
+
+        // Other chats label by their own policy
+        assert!(
+            index
+                .nearest(ChatId(-200), "Any good books on Rust for a beginner?")
+                .await
+                .is_empty()
+        );
+
+        // Vectors of another embedder are left alone, and re-embedded by seeding
+        let index = SemanticIndex::new(
+            Some(Box::new(LocalEmbedder)),
+            "other",
+            state.clone(),
+            Default::default(),
+        );
+        assert!(
+            index
+                .nearest(chat_id, "Any good books on Rust for a beginner?")
+                .await
+                .is_empty()
+        );
+
+        let disabled = SemanticIndex::new(None, "", state, Default::default());
+        assert!(disabled.nearest(chat_id, "anything").await.is_empty());
+    }
+
+    struct Flaky(bool);
+
+    #[async_trait]
+    impl Embedder for Flaky {
+        async fn embed(&self, _: ChatId, _: &str) -> anyhow::Result<Vec<f32>> {
+            if self.0 {
+                Ok(vec![1.0])
+            } else {
+                anyhow::bail!("unavailable")
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remote_embeddings_are_guarded() {
+        let chat_id = ChatId(-100);
+        let state = Arc::new(AppState::new());
+        let mut settings = crate::test_support::settings(Default::default());
+        settings.budget.per_chat.requests_per_minute = Some(1);
+        settings.resilience.circuit_failure_threshold = 1;
+
+        // Chats without labeled messages don't pay for a request
+        let index = SemanticIndex::new(
+            Some(Box::new(Guarded::new(
+                Box::new(Flaky(true)),
+                state.clone(),
+                &settings,
+            ))),
+            "flaky",
+            state.clone(),
+            Default::default(),
+        );
+        assert!(index.nearest(chat_id, "hi").await.is_empty());
+        assert!(state.usage_report(chat_id, 1).is_empty());
+
+        let embedder = Guarded::new(Box::new(Flaky(true)), state.clone(), &settings);
+        embedder.embed(chat_id, "12345678").await.unwrap();
+        assert!(embedder.embed(chat_id, "12345678").await.is_err());
+        let report = state.usage_report(chat_id, 1);
+        assert_eq!((report[0].1.requests, report[0].1.prompt_tokens), (1, 2));
+
+        // Once failing, the endpoint is left alone for the cooldown
+        settings.budget = Default::default();
+        let embedder = Guarded::new(Box::new(Flaky(false)), state.clone(), &settings);
+        embedder.embed(chat_id, "hi").await.unwrap_err();
+        let e = embedder.embed(chat_id, "hi").await.unwrap_err();
+        assert!(e.to_string().contains("circuit open"));
+    }
+}
@@ -0,0 +1,307 @@
+use crate::bot;
+use crate::config::Settings;
+use crate::detect::{self, MsgType, SenderProfile, SpamCheckRequest};
+use crate::links::Links;
+use crate::normalize;
+use crate::rules::Rules;
+use crate::state::AppState;
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use teloxide::types::Message;
+use tokio::fs;
+
+const CATEGORIES: [MsgType; 6] = [
+    MsgType::Scam,
+    MsgType::Phishing,
+    MsgType::NotSuitableForWork,
+    MsgType::UnsolicitedPromotion,
+    MsgType::OtherSpam,
+    MsgType::NotSpam,
+];
+
+/// A line of the dataset
+///
+/// ```json
+/// {"text": "Free crypto, DM me", "context": ["hi all", "hello"], "label": "scam"}
+/// ```
+#[derive(Debug, Deserialize)]
+struct Sample {
+    text: String,
+    /// Preceding messages of the chat, oldest first
+    #[serde(default)]
+    context: Vec<String>,
+    label: MsgType,
+}
+
+/// Build a plain text group message as the bot would receive it
+fn synthetic_message(id: i32, user_id: u64, text: &str) -> anyhow::Result<Message> {
+    serde_json::from_value(json!({
+        "message_id": id,
+        "date": 0,
+        "chat": {"id": -1, "type": "supergroup", "title": "Evaluation"},
+        "from": {"id": user_id, "is_bot": false, "first_name": format!("User{}", user_id)},
+        "text": text,
+    }))
+    .context("Failed to build message")
+}
+
+#[derive(Default)]
+struct Report {
+    /// Counts indexed by [actual][predicted] in the order of `CATEGORIES`
+    confusion: [[u64; CATEGORIES.len()]; CATEGORIES.len()],
+    errors: u64,
+    latencies: Vec<Duration>,
+}
+
+fn index(msg_type: MsgType) -> usize {
+    CATEGORIES.iter().position(|c| *c == msg_type).unwrap()
+}
+
+fn ratio(numerator: u64, denominator: u64) -> String {
+    if denominator == 0 {
+        "-".to_string()
+    } else {
+        format!("{:.3}", numerator as f64 / denominator as f64)
+    }
+}
+
+impl Report {
+    fn record(&mut self, actual: MsgType, predicted: MsgType) {
+        self.confusion[index(actual)][index(predicted)] += 1;
+    }
+
+    fn print(&mut self) {
+        let total = self.confusion.iter().flatten().sum::<u64>();
+        let correct = (0..CATEGORIES.len())
+            .map(|i| self.confusion[i][i])
+            .sum::<u64>();
+
+        println!("Confusion matrix (rows: label, columns: prediction)");
+        print!("{:>24}", "");
+        for (i, _) in CATEGORIES.iter().enumerate() {
+            print!("{:>8}", i);
+        }
+        println!();
+        for (i, category) in CATEGORIES.iter().enumerate() {
+            print!("{:>20} {:>2} ", format!("{:?}", category), i);
+            for count in self.confusion[i] {
+                print!("{:>8}", count);
+            }
+            println!();
+        }
+
+        println!(
+            "\n{:>24}{:>10}{:>10}{:>10}",
+            "", "precision", "recall", "support"
+        );
+        for (i, category) in CATEGORIES.iter().enumerate() {
+            let predicted = (0..CATEGORIES.len())
+                .map(|j| self.confusion[j][i])
+                .sum::<u64>();
+            let actual = self.confusion[i].iter().sum::<u64>();
+            println!(
+                "{:>24}{:>10}{:>10}{:>10}",
+                format!("{:?}", category),
+                ratio(self.confusion[i][i], predicted),
+                ratio(self.confusion[i][i], actual),
+                actual
+            );
+        }
+
+        // Spam of the wrong category still gets removed, so the binary view matters most
+        let ham = index(MsgType::NotSpam);
+        let predicted_ham = (0..CATEGORIES.len())
+            .map(|i| self.confusion[i][ham])
+            .sum::<u64>();
+        let spam_predicted = total - predicted_ham;
+        let spam_actual = total - self.confusion[ham].iter().sum::<u64>();
+        let spam_caught = spam_actual - (predicted_ham - self.confusion[ham][ham]);
+        println!(
+            "{:>24}{:>10}{:>10}{:>10}",
+            "Spam (any category)",
+            ratio(spam_caught, spam_predicted),
+            ratio(spam_caught, spam_actual),
+            spam_actual
+        );
+
+        println!(
+            "\nAccuracy: {} ({}/{}), classifier errors: {}",
+            ratio(correct, total),
+            correct,
+            total,
+            self.errors
+        );
+
+        self.latencies.sort();
+        if let Some(max) = self.latencies.last() {
+            let percentile = |p: usize| self.latencies[(self.latencies.len() - 1) * p / 100];
+            let mean = self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32;
+            println!(
+                "Latency: mean {:?}, p50 {:?}, p95 {:?}, max {:?}",
+                mean,
+                percentile(50),
+                percentile(95),
+                max
+            );
+        }
+    }
+}
+
+/// Forget the samples wherever the state learned them from, so that the local model isn't
+/// measured on its own training data
+fn hold_out(state: &AppState, samples: &[Sample]) {
+    let held_out = samples
+        .iter()
+        .map(|s| normalize::skeleton(&s.text))
+        .collect::<HashSet<_>>();
+    for mut examples in state.labeled_examples.iter_mut() {
+        examples.retain(|e| !held_out.contains(&normalize::skeleton(&e.text)));
+    }
+    for mut ham in state.trusted_ham.iter_mut() {
+        ham.retain(|text| !held_out.contains(&normalize::skeleton(text)));
+    }
+}
+
+/// Replay a labeled JSONL dataset through the rules and the configured classifier and print
+/// how well the verdicts match the labels
+pub async fn run(settings: &Settings, path: &str) -> anyhow::Result<()> {
+    let content = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read dataset {}", path))?;
+    let samples = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str::<Sample>(line).with_context(|| format!("Line {}", i + 1))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let rules = Arc::new(match &settings.rules_path {
+        Some(path) => Rules::load_from_file(path).await?,
+        None => Rules::default(),
+    });
+    // The local model is evaluated as of the current state, less the dataset. Known spam is
+    // specific to the chats it was seen in and doesn't apply here.
+    let state = Arc::new(AppState::load_from_file(&settings.state_path).await?);
+    hold_out(&state, &samples);
+    state.retrain_local_model(settings.local_model.min_samples);
+    // Budgets are meant for live chats, the whole dataset is evaluated regardless
+    let settings = &Settings {
+        budget: Default::default(),
+        ..settings.clone()
+    };
+    let classifier = detect::build_classifier(settings, rules.clone(), state.clone())?;
+
+    let mut report = Report::default();
+    for (i, sample) in samples.iter().enumerate() {
+        let context = sample
+            .context
+            .iter()
+            .enumerate()
+            .map(|(j, text)| synthetic_message(j as i32 + 1, j as u64 + 2, text))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let message = synthetic_message(context.len() as i32 + 1, 1, &sample.text)?;
+
+        if let Some(res) = bot::pre_filter(message.chat.id, &sample.text, &rules, &state, settings)
+        {
+            report.record(sample.label, res.msg_type);
+            continue;
+        }
+
+        let links = Links::extract(&message);
+        let started = Instant::now();
+        let res = classifier
+            .check_spam(&SpamCheckRequest {
+                message: &message,
+                context: &context,
+                media: None,
+                policy: None,
+                links: &links,
+                sender: SenderProfile::default(),
+                examples: &[],
+                similar: &[],
+            })
+            .await;
+        report.latencies.push(started.elapsed());
+
+        match res {
+            Ok(res) => report.record(sample.label, res.msg_type),
+            Err(e) => {
+                tracing::warn!("Sample {} failed: {:#}", i + 1, e);
+                report.errors += 1;
+            }
+        }
+    }
+
+    report.print();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::examples::LabeledExample;
+    use crate::test_support::{self, MockGemini, Reply};
+
+    #[tokio::test]
+    async fn test_eval_against_mock_server() {
+        let mock = MockGemini::start(vec![
+            Reply::Verdict(MsgType::Scam, 0.9),
+            Reply::Verdict(MsgType::NotSpam, 0.9),
+        ])
+        .await;
+        let mut settings = test_support::settings(mock.settings());
+        settings.state_path = "/nonexistent/state.json".to_string();
+
+        let path = std::env::temp_dir().join(format!("eval-{}.jsonl", std::process::id()));
+        fs::write(
+            &path,
+            "{\"text\": \"Free crypto\", \"label\": \"scam\"}\n\n{\"text\": \"hi\", \"context\": [\"hello\"], \"label\": \"not_spam\"}\n",
+        )
+        .await
+        .unwrap();
+
+        let res = run(&settings, path.to_str().unwrap()).await;
+        fs::remove_file(&path).await.unwrap();
+        res.unwrap();
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[test]
+    fn test_hold_out_dataset() {
+        let state = AppState::new();
+        state
+            .trusted_ham
+            .insert(-1, ["Hello  ALL".into(), "bye".into()].into());
+        state.labeled_examples.insert(
+            -1,
+            [LabeledExample {
+                text: "Free crypto for everyone who DMs me today".to_string(),
+                label: MsgType::Scam,
+                labeled_at: 0,
+            }]
+            .into(),
+        );
+        let samples = [
+            Sample {
+                text: "hello all".to_string(),
+                context: Vec::new(),
+                label: MsgType::NotSpam,
+            },
+            Sample {
+                text: "Free crypto for everyone who DMs me today".to_string(),
+                context: Vec::new(),
+                label: MsgType::Scam,
+            },
+        ];
+
+        hold_out(&state, &samples);
+        assert_eq!(state.trusted_ham.get(&-1).unwrap().len(), 1);
+        assert!(state.labeled_examples.get(&-1).unwrap().is_empty());
+    }
+}
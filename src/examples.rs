@@ -0,0 +1,80 @@
+use crate::detect::MsgType;
+use crate::normalize;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+
+/// A message whose verdict a human confirmed or overturned
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LabeledExample {
+    pub text: String,
+    /// `NotSpam` for dismissed verdicts, the category of the verdict for kicks
+    pub label: MsgType,
+    /// Unix timestamp of the label
+    pub labeled_at: i64,
+}
+
+/// Pick up to `count` recent examples for a few-shot prompt. Labels take turns, so confirmed
+/// spam and false positives are both shown, and copies of the same text are skipped.
+pub fn select(examples: &VecDeque<LabeledExample>, count: usize) -> Vec<LabeledExample> {
+    // Newest first, grouped by label in order of their most recent example
+    let mut groups: Vec<(MsgType, Vec<&LabeledExample>)> = Vec::new();
+    let mut seen = HashSet::new();
+    for example in examples.iter().rev() {
+        if !seen.insert(normalize::skeleton(&example.text)) {
+            continue;
+        }
+        match groups.iter_mut().find(|(label, _)| *label == example.label) {
+            Some((_, group)) => group.push(example),
+            None => groups.push((example.label, vec![example])),
+        }
+    }
+
+    let mut selected = Vec::new();
+    for i in 0.. {
+        let round = groups
+            .iter()
+            .filter_map(|(_, group)| group.get(i))
+            .collect::<Vec<_>>();
+        if round.is_empty() {
+            break;
+        }
+        for example in round {
+            if selected.len() == count {
+                return selected;
+            }
+            selected.push((*example).clone());
+        }
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example(text: &str, label: MsgType) -> LabeledExample {
+        LabeledExample {
+            text: text.to_string(),
+            label,
+            labeled_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_select_diverse_recent() {
+        let examples = VecDeque::from([
+            example("old ham", MsgType::NotSpam),
+            example("pump signal", MsgType::UnsolicitedPromotion),
+            example("free crypto", MsgType::Scam),
+            example("FREE  crypto", MsgType::Scam),
+            example("wallet drainer", MsgType::Scam),
+        ]);
+
+        let texts = select(&examples, 3)
+            .into_iter()
+            .map(|e| e.text)
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["wallet drainer", "pump signal", "old ham"]);
+        assert_eq!(select(&examples, 10).len(), 4);
+    }
+}
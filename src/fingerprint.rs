@@ -0,0 +1,78 @@
+use crate::normalize;
+
+/// Characters per shingle, so that changing a word only alters the shingles overlapping it
+const SHINGLE_CHARS: usize = 4;
+
+/// Texts with fewer words are too short for a meaningful fingerprint
+const MIN_WORDS: usize = 6;
+
+/// FNV-1a, stable across builds unlike the standard library hashers, so stored fingerprints
+/// stay comparable after an upgrade
+pub fn fnv1a(data: &str) -> u64 {
+    data.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// SimHash of the word shingles of the skeleton of a text. Similar texts get fingerprints
+/// differing in few bits.
+pub fn simhash(text: &str) -> Option<u64> {
+    let skeleton = normalize::skeleton(text);
+    let words = skeleton
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>();
+    if words.len() < MIN_WORDS {
+        return None;
+    }
+
+    let chars = words.join(" ").chars().collect::<Vec<_>>();
+    let mut weights = [0i32; 64];
+    for shingle in chars.windows(SHINGLE_CHARS) {
+        let hash = fnv1a(&shingle.iter().collect::<String>());
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            *weight += if hash >> bit & 1 == 1 { 1 } else { -1 };
+        }
+    }
+
+    Some(
+        weights
+            .iter()
+            .enumerate()
+            .filter(|(_, weight)| **weight > 0)
+            .fold(0, |hash, (bit, _)| hash | 1 << bit),
+    )
+}
+
+/// Share of equal bits of two fingerprints, from 0.0 to 1.0
+pub fn similarity(a: u64, b: u64) -> f64 {
+    1.0 - (a ^ b).count_ones() as f64 / 64.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_near_duplicates() {
+        let original = simhash(
+            "Hello everyone! I made 5000$ last week trading with the help of Mrs. Anna, \
+             message her on Telegram to start earning today, limited spots",
+        )
+        .unwrap();
+        let variant = simhash(
+            "Hi everyone! I made 7000$ last week trading with the help of Mrs. Anna, \
+             message her on telegram to start earning now, limited spots",
+        )
+        .unwrap();
+        let unrelated = simhash(
+            "Has anyone tried the new release? The build fails on my machine with a linker \
+             error about missing symbols",
+        )
+        .unwrap();
+
+        assert!(similarity(original, variant) >= 0.9);
+        assert!(similarity(original, unrelated) < 0.8);
+        assert_eq!(simhash("too short to tell"), None);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::detect::{MsgType, SpamCheckResult};
+use crate::normalize;
+use reqwest::Url;
+use teloxide::types::{Message, MessageEntityKind};
+
+/// A link found in a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub url: String,
+    /// Lowercased host of the URL, if it has one
+    pub domain: Option<String>,
+    /// Text shown in place of the URL for hidden links
+    pub shown_as: Option<String>,
+}
+
+/// Links and mentions found in the entities of a message
+#[derive(Debug, Clone, Default)]
+pub struct Links {
+    pub links: Vec<Link>,
+    /// @usernames and names of mentioned users
+    pub mentions: Vec<String>,
+}
+
+/// Whether `host` is `domain` or one of its subdomains
+pub fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|rest| rest.ends_with('.'))
+}
+
+/// Lowercase a user supplied domain, dropping any scheme, trailing slash or leading dot.
+/// `None` if it is empty or has a path, which would widen the entry to the whole site.
+pub fn normalize_domain(domain: &str) -> Option<String> {
+    let domain = domain.trim().to_lowercase();
+    let domain = domain.split_once("://").map_or(domain.as_str(), |(_, d)| d);
+    let domain = domain.strip_suffix('/').unwrap_or(domain);
+    let domain = domain.trim_start_matches('.');
+    if domain.is_empty() || domain.contains(['/', '?', '#']) || domain.contains(char::is_whitespace)
+    {
+        return None;
+    }
+    Some(domain.to_string())
+}
+
+fn parse_domain(url: &str) -> Option<String> {
+    let parsed = Url::parse(url)
+        .or_else(|_| Url::parse(&format!("http://{}", url)))
+        .ok()?;
+    parsed.host_str().map(|h| h.to_lowercase())
+}
+
+impl Links {
+    /// Extract URLs, hidden text links and mentions from the text or caption entities
+    pub fn extract(message: &Message) -> Self {
+        let mut links = Self::default();
+
+        let entities = message
+            .parse_entities()
+            .or_else(|| message.parse_caption_entities())
+            .unwrap_or_default();
+
+        for entity in entities {
+            match entity.kind() {
+                MessageEntityKind::Url => links.links.push(Link {
+                    url: entity.text().to_string(),
+                    domain: parse_domain(entity.text()),
+                    shown_as: None,
+                }),
+                MessageEntityKind::TextLink { url } => links.links.push(Link {
+                    url: url.to_string(),
+                    domain: url.host_str().map(|h| h.to_lowercase()),
+                    shown_as: Some(entity.text().to_string()),
+                }),
+                MessageEntityKind::Mention => links.mentions.push(entity.text().to_string()),
+                MessageEntityKind::TextMention { user } => {
+                    links
+                        .mentions
+                        .push(format!("{} (User{})", entity.text(), user.id))
+                }
+                _ => {}
+            }
+        }
+
+        links
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty() && self.mentions.is_empty()
+    }
+
+    /// Verdict from the domain denylist: any denied domain makes the message phishing. Hosts
+    /// are also compared by their skeleton, so look-alike letters don't get around the list.
+    pub fn check_domains(&self, denied: &[String]) -> Option<SpamCheckResult> {
+        let domain = self
+            .links
+            .iter()
+            .flat_map(|l| [l.domain.clone(), parse_domain(&normalize::skeleton(&l.url))])
+            .flatten()
+            .find(|d| denied.iter().any(|denied| domain_matches(d, denied)))?;
+        Some(SpamCheckResult::certain(
+            MsgType::Phishing,
+            format!("Links to denied domain {}", domain),
+        ))
+    }
+
+    /// Forget links to allowed domains, so that they aren't held against the message. The rest
+    /// of the message is still checked, spam may well link to a popular site.
+    pub fn drop_allowed(&mut self, allowed: &[String]) {
+        self.links.retain(|l| {
+            !l.domain
+                .as_deref()
+                .is_some_and(|d| allowed.iter().any(|allowed| domain_matches(d, allowed)))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(url: &str) -> Link {
+        Link {
+            url: url.to_string(),
+            domain: parse_domain(url),
+            shown_as: None,
+        }
+    }
+
+    #[test]
+    fn test_domain_lists() {
+        let allowed = vec![normalize_domain("https://Example.org/").unwrap()];
+        let denied = vec![normalize_domain(".evil.example").unwrap()];
+        assert_eq!(normalize_domain("t.me/scam"), None);
+        assert_eq!(normalize_domain("https://t.me/?start=1"), None);
+        assert_eq!(normalize_domain("/"), None);
+
+        assert!(domain_matches("a.b.example.org", "example.org"));
+        assert!(!domain_matches("notexample.org", "example.org"));
+
+        let mut links = Links {
+            links: vec![link("docs.example.org/x"), link("https://other.example")],
+            mentions: vec![],
+        };
+        assert!(links.check_domains(&denied).is_none());
+        links.drop_allowed(&allowed);
+        assert_eq!(links.links, vec![link("https://other.example")]);
+
+        let links = Links {
+            links: vec![
+                link("https://example.org"),
+                link("https://login.evil.example"),
+            ],
+            mentions: vec![],
+        };
+        assert_eq!(
+            links.check_domains(&denied).unwrap().msg_type,
+            MsgType::Phishing
+        );
+
+        // Look-alike letters and hidden link targets
+        let links = Links {
+            links: vec![link("https://secure.еvil.example")],
+            mentions: vec![],
+        };
+        assert!(links.check_domains(&denied).is_some());
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": {"id": -100, "type": "supergroup", "title": "Test"},
+            "from": {"id": 1, "is_bot": false, "first_name": "Test"},
+            "text": "Claim here",
+            "entities": [{
+                "type": "text_link", "offset": 6, "length": 4,
+                "url": "https://login.evil.example/",
+            }],
+        }))
+        .unwrap();
+        assert!(Links::extract(&message).check_domains(&denied).is_some());
+    }
+}
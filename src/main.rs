@@ -1,14 +1,33 @@
+mod bayes;
 mod bot;
+mod budget;
+mod cache;
 mod config;
 mod detect;
+mod embedding;
+mod eval;
+mod examples;
+mod fingerprint;
+mod links;
+mod media;
+mod normalize;
 mod post;
+mod rules;
+mod sender;
 mod state;
+#[cfg(test)]
+mod test_support;
+mod usage;
 
+use crate::cache::VerdictCache;
 use crate::config::Settings;
-use crate::detect::Agent;
+use crate::embedding::SemanticIndex;
+use crate::rules::Rules;
 use crate::state::AppState;
+use anyhow::Context;
 use std::sync::Arc;
 use teloxide::Bot;
+use teloxide::types::ChatId;
 use tokio::time::{self, Duration};
 
 #[tokio::main]
@@ -19,6 +38,20 @@ async fn main() -> anyhow::Result<()> {
 
     let settings = Arc::new(Settings::new().expect("Failed to load settings"));
 
+    // `eval <dataset.jsonl>` measures the classifier against labeled messages instead of
+    // running the bot
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).is_some_and(|a| a == "eval") {
+        let path = args
+            .get(2)
+            .context("Usage: tg-anti-spam eval <dataset.jsonl>")?;
+        return eval::run(&settings, path).await;
+    }
+    anyhow::ensure!(
+        !settings.tg_bot_token.is_empty(),
+        "tg_bot_token is required"
+    );
+
     let state = match AppState::load_from_file(&settings.state_path).await {
         Ok(s) => s,
         Err(e) => {
@@ -28,7 +61,35 @@ async fn main() -> anyhow::Result<()> {
     };
     let state = Arc::new(state);
 
-    let agent = Arc::new(Agent::new(settings.gemini_api_key.clone()));
+    let rules = match &settings.rules_path {
+        Some(path) => Rules::load_from_file(path).await?,
+        None => Rules::default(),
+    };
+    let rules = Arc::new(rules);
+
+    let classifier = detect::build_classifier(&settings, rules.clone(), state.clone())?;
+
+    let cache = Arc::new(VerdictCache::new(
+        Duration::from_secs(settings.cache_ttl_secs),
+        settings.cache_capacity,
+    ));
+
+    let semantic = Arc::new(SemanticIndex::from_settings(&settings, state.clone())?);
+    tokio::spawn({
+        let semantic = semantic.clone();
+        let examples = state
+            .labeled_examples
+            .iter()
+            .flat_map(|e| {
+                let chat_id = ChatId(*e.key());
+                e.value()
+                    .iter()
+                    .map(|example| (chat_id, example.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        async move { semantic.seed(examples).await }
+    });
 
     let state_for_save = state.clone();
     let state_path = settings.state_path.clone(); // Clone for 'static lifetime
@@ -45,7 +106,7 @@ async fn main() -> anyhow::Result<()> {
     let bot = Bot::new(settings.tg_bot_token.clone());
     tracing::info!("Starting Anti-Spam Bot...");
 
-    bot::run_bot(bot, agent, state, settings).await?;
+    bot::run_bot(bot, classifier, rules, cache, semantic, state, settings).await?;
 
     Ok(())
 }
@@ -0,0 +1,174 @@
+use teloxide::net::Download;
+use teloxide::prelude::*;
+use teloxide::types::{FileMeta, PhotoSize};
+
+/// Media attached to a message, downloaded so that it can be shown to the classifier
+pub struct Media {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Whether the classifier backends can take this MIME type inline
+fn is_supported(mime_type: &str) -> bool {
+    mime_type.starts_with("image/")
+        || mime_type.starts_with("video/")
+        || mime_type == "application/pdf"
+}
+
+/// Pick the file worth sending to the classifier, falling back to the thumbnail if the file
+/// itself is too large or of an unsupported type. Stickers are not worth the tokens, they are
+/// only described as such.
+fn pick_file(message: &Message, max_bytes: u32) -> Option<(&FileMeta, String)> {
+    // Photo sizes are ordered from the smallest to the largest
+    if let Some(sizes) = message.photo() {
+        return sizes
+            .iter()
+            .rev()
+            .find(|p| p.file.size <= max_bytes)
+            .map(|p| (&p.file, "image/jpeg".to_string()));
+    }
+
+    let (file, mime_type, thumbnail): (&FileMeta, Option<String>, Option<&PhotoSize>) =
+        if let Some(v) = message.video() {
+            (
+                &v.file,
+                Some(
+                    v.mime_type
+                        .as_ref()
+                        .map_or("video/mp4".to_string(), |m| m.to_string()),
+                ),
+                v.thumbnail.as_ref(),
+            )
+        } else if let Some(v) = message.video_note() {
+            (&v.file, Some("video/mp4".to_string()), v.thumbnail.as_ref())
+        } else if let Some(a) = message.animation() {
+            (
+                &a.file,
+                a.mime_type.as_ref().map(|m| m.to_string()),
+                a.thumbnail.as_ref(),
+            )
+        } else if let Some(d) = message.document() {
+            (
+                &d.file,
+                d.mime_type.as_ref().map(|m| m.to_string()),
+                d.thumbnail.as_ref(),
+            )
+        } else {
+            return None;
+        };
+
+    match mime_type {
+        Some(mime_type) if is_supported(&mime_type) && file.size <= max_bytes => {
+            Some((file, mime_type))
+        }
+        _ => thumbnail
+            .filter(|t| t.file.size <= max_bytes)
+            .map(|t| (&t.file, "image/jpeg".to_string())),
+    }
+}
+
+/// Unique ID of the main file attached to a message, stable across chats and reposts
+pub fn file_unique_id(message: &Message) -> Option<&str> {
+    let file = if let Some(sizes) = message.photo() {
+        &sizes.last()?.file
+    } else if let Some(v) = message.video() {
+        &v.file
+    } else if let Some(v) = message.video_note() {
+        &v.file
+    } else if let Some(a) = message.animation() {
+        &a.file
+    } else if let Some(s) = message.sticker() {
+        &s.file
+    } else if let Some(d) = message.document() {
+        &d.file
+    } else {
+        return None;
+    };
+    Some(&file.unique_id)
+}
+
+/// Download the media attached to a message, if any
+pub async fn download(bot: &Bot, message: &Message, max_bytes: u32) -> Option<Media> {
+    let (meta, mime_type) = pick_file(message, max_bytes)?;
+
+    let file = match bot.get_file(meta.id.clone()).await {
+        Ok(f) => f,
+        Err(e) => {
+            tracing::error!("Failed to get media file info: {}", e);
+            return None;
+        }
+    };
+
+    let mut data = Vec::with_capacity(file.size as usize);
+    if let Err(e) = bot.download_file(&file.path, &mut data).await {
+        tracing::error!("Failed to download media: {}", e);
+        return None;
+    }
+
+    Some(Media { mime_type, data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(media: serde_json::Value) -> Message {
+        let mut message = json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": {"id": -100, "type": "supergroup", "title": "Test"},
+            "from": {"id": 1, "is_bot": false, "first_name": "Test"},
+        });
+        message
+            .as_object_mut()
+            .unwrap()
+            .extend(media.as_object().unwrap().clone());
+        serde_json::from_value(message).unwrap()
+    }
+
+    fn file(id: &str, size: u32) -> serde_json::Value {
+        json!({"file_id": id, "file_unique_id": id, "file_size": size, "width": 1, "height": 1})
+    }
+
+    #[test]
+    fn test_pick_file() {
+        let photo = message(json!({"photo": [file("small", 10), file("large", 1000)]}));
+        assert_eq!(pick_file(&photo, 1000).unwrap().0.id, "large");
+        assert_eq!(pick_file(&photo, 999).unwrap().0.id, "small");
+        assert!(pick_file(&photo, 9).is_none());
+        assert_eq!(file_unique_id(&photo), Some("large"));
+
+        let mut video = file("video", 1000);
+        video["duration"] = 1.into();
+        video["mime_type"] = "video/mp4".into();
+        video["thumbnail"] = file("thumbnail", 10);
+        let video = message(json!({ "video": video }));
+        let (meta, mime_type) = pick_file(&video, 1000).unwrap();
+        assert_eq!(
+            (meta.id.as_str(), mime_type.as_str()),
+            ("video", "video/mp4")
+        );
+        // Too large, the thumbnail is shown instead
+        let (meta, mime_type) = pick_file(&video, 100).unwrap();
+        assert_eq!(
+            (meta.id.as_str(), mime_type.as_str()),
+            ("thumbnail", "image/jpeg")
+        );
+        assert!(pick_file(&video, 9).is_none());
+
+        let mut document = file("document", 10);
+        document["mime_type"] = "application/zip".into();
+        let document = message(json!({ "document": document }));
+        assert!(pick_file(&document, 1000).is_none());
+        assert_eq!(file_unique_id(&document), Some("document"));
+
+        let mut sticker = file("sticker", 10);
+        sticker["type"] = "regular".into();
+        sticker["is_animated"] = false.into();
+        sticker["is_video"] = false.into();
+        let sticker = message(json!({ "sticker": sticker }));
+        assert!(pick_file(&sticker, 1000).is_none());
+        assert_eq!(file_unique_id(&sticker), Some("sticker"));
+    }
+}
@@ -0,0 +1,294 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+
+/// Total number of obfuscation signals from which a text counts as heavily obfuscated
+const HEAVY_OBFUSCATION: usize = 3;
+
+/// Separators used to break up words letter by letter, e.g. `f.r.e.e`
+const LETTER_SEPARATORS: &[char] = &['.', '-', '_', '*', '|', '·', '/'];
+
+/// Tricks found while normalizing a text
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Obfuscation {
+    /// Zero-width and other invisible characters inside words
+    pub invisible: usize,
+    /// Letters from other scripts or styled (fullwidth, mathematical, ...) letters mixed into
+    /// Latin words
+    pub lookalikes: usize,
+    /// Combining marks decorating letters
+    pub marks: usize,
+    /// Letters broken up by separators
+    pub spaced_letters: usize,
+}
+
+impl Obfuscation {
+    pub fn is_heavy(&self) -> bool {
+        self.invisible + self.lookalikes + self.marks + self.spaced_letters >= HEAVY_OBFUSCATION
+    }
+
+    /// Names of the techniques used, for the classifier
+    pub fn techniques(&self) -> Vec<&'static str> {
+        [
+            (self.invisible, "invisible characters"),
+            (self.lookalikes, "look-alike characters"),
+            (self.marks, "combining marks"),
+            (self.spaced_letters, "letters broken up by separators"),
+        ]
+        .into_iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+/// A text reduced to a canonical skeleton
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Normalized {
+    /// Lowercase text with compatibility characters, look-alikes, invisible characters,
+    /// combining marks and spaced out letters folded away
+    pub skeleton: String,
+    pub obfuscation: Obfuscation,
+}
+
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}'
+            | '\u{034F}'
+            | '\u{061C}'
+            | '\u{115F}'
+            | '\u{1160}'
+            | '\u{17B4}'
+            | '\u{17B5}'
+            | '\u{180E}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{206F}'
+            | '\u{3164}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FEFF}'
+            | '\u{FFA0}'
+            | '\u{E0000}'..='\u{E007F}'
+    )
+}
+
+/// Lowercase Latin letter a Cyrillic or Greek letter is commonly passed off as. Case matters,
+/// e.g. `Ν` looks like `N` but `ν` like `v`.
+fn confusable(c: char) -> Option<char> {
+    Some(match c {
+        'а' | 'А' | 'α' | 'Α' => 'a',
+        'в' | 'В' | 'ь' | 'Ь' | 'β' | 'Β' => 'b',
+        'с' | 'С' | 'ϲ' | 'Ϲ' => 'c',
+        'ԁ' => 'd',
+        'е' | 'Е' | 'ё' | 'Ё' | 'є' | 'ε' | 'Ε' => 'e',
+        'ɡ' => 'g',
+        'н' | 'Н' | 'һ' | 'η' | 'Η' => 'h',
+        'і' | 'І' | 'ї' | 'Ї' | 'ι' | 'Ι' | 'ı' => 'i',
+        'ј' | 'Ј' | 'ϳ' => 'j',
+        'к' | 'К' | 'κ' | 'Κ' => 'k',
+        'ӏ' | 'Ӏ' => 'l',
+        'м' | 'М' | 'Μ' => 'm',
+        'п' | 'Ν' => 'n',
+        'о' | 'О' | 'ο' | 'Ο' | 'σ' => 'o',
+        'р' | 'Р' | 'ρ' | 'Ρ' => 'p',
+        'ԛ' => 'q',
+        'ѕ' | 'Ѕ' => 's',
+        'т' | 'Т' | 'τ' | 'Τ' => 't',
+        'υ' => 'u',
+        'ν' => 'v',
+        'ԝ' | 'ѡ' | 'ω' => 'w',
+        'х' | 'Х' | 'χ' | 'Χ' => 'x',
+        'у' | 'У' | 'ү' | 'Ү' | 'γ' | 'Υ' => 'y',
+        'Ζ' => 'z',
+        _ => return None,
+    })
+}
+
+/// Join a word spelled out with separators between its letters, e.g. `f.r.e.e`
+fn join_separated(word: &str) -> Option<String> {
+    let chars = word.chars().collect::<Vec<_>>();
+    if chars.len() < 7 || chars.len() % 2 == 0 {
+        return None;
+    }
+
+    let spaced = chars.iter().enumerate().all(|(i, c)| {
+        if i % 2 == 0 {
+            c.is_alphanumeric()
+        } else {
+            *c == chars[1] && LETTER_SEPARATORS.contains(c)
+        }
+    });
+    spaced.then(|| chars.iter().step_by(2).collect())
+}
+
+/// Join a run of single letter words like `m o n e y`, shorter runs are kept as they are. Such
+/// runs are too common in ordinary text (e.g. `Plan A B C`) to count as obfuscation.
+fn flush_run(run: &mut Vec<String>, words: &mut Vec<String>) {
+    if run.len() >= 3 {
+        words.push(run.concat());
+        run.clear();
+    } else {
+        words.append(run);
+    }
+}
+
+/// Joiners shaping the letters of scripts like Arabic, Persian or Devanagari
+fn is_joiner(c: char) -> bool {
+    matches!(c, '\u{200C}' | '\u{200D}')
+}
+
+/// Fold a word to its skeleton. Only words containing Latin letters are taken apart, look-alikes,
+/// styled letters, invisible characters and marks in them are obfuscation. Words in other
+/// scripts keep their marks and joiners.
+fn fold_word(word: &str, obfuscation: &mut Obfuscation) -> String {
+    let latin = word.nfkd().any(|c| c.is_ascii_alphabetic());
+    if !latin {
+        return word
+            .chars()
+            .filter(|c| !is_invisible(*c) || is_joiner(*c))
+            .nfkc()
+            .flat_map(char::to_lowercase)
+            .collect();
+    }
+
+    // Compatibility decomposition turns fullwidth, mathematical and circled letters into plain
+    // ones and splits accents off their letters
+    let mut folded = String::with_capacity(word.len());
+    let mut after_letter = false;
+    for c in word.chars() {
+        if is_invisible(c) {
+            if after_letter {
+                obfuscation.invisible += 1;
+            }
+            continue;
+        }
+        if is_combining_mark(c) {
+            obfuscation.marks += 1;
+            continue;
+        }
+
+        let mut decomposed = std::iter::once(c)
+            .nfkd()
+            .filter(|d| !is_combining_mark(*d))
+            .peekable();
+        // Accented letters are fine, letters that are plain ASCII in disguise are not
+        let styled = !c.is_ascii()
+            && decomposed.peek().is_some_and(|d| d.is_ascii_alphanumeric())
+            && !std::iter::once(c)
+                .nfd()
+                .next()
+                .is_some_and(|d| d.is_ascii());
+        if styled {
+            obfuscation.lookalikes += 1;
+        }
+
+        for d in decomposed {
+            match confusable(d) {
+                Some(latin_c) => {
+                    obfuscation.lookalikes += 1;
+                    folded.push(latin_c);
+                }
+                None => folded.extend(d.to_lowercase()),
+            }
+        }
+        after_letter = c.is_alphanumeric();
+    }
+    folded
+}
+
+/// Reduce a text to its skeleton, noting the obfuscation found on the way
+pub fn normalize(text: &str) -> Normalized {
+    let mut obfuscation = Obfuscation::default();
+    let mut words = Vec::new();
+    let mut run = Vec::new();
+
+    for word in text.split_whitespace() {
+        let mut word = fold_word(word, &mut obfuscation);
+        if word.is_empty() {
+            continue;
+        }
+
+        if let Some(joined) = join_separated(&word) {
+            obfuscation.spaced_letters += joined.chars().count();
+            word = joined;
+        }
+
+        // Only letters are spelled out, lists of digits are just that
+        let mut chars = word.chars();
+        if chars.next().is_some_and(|c| c.is_ascii_alphabetic()) && chars.next().is_none() {
+            run.push(word);
+        } else {
+            flush_run(&mut run, &mut words);
+            words.push(word);
+        }
+    }
+    flush_run(&mut run, &mut words);
+
+    Normalized {
+        skeleton: words.join(" "),
+        obfuscation,
+    }
+}
+
+/// Shorthand for the skeleton of a text
+pub fn skeleton(text: &str) -> String {
+    normalize(text).skeleton
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skeleton() {
+        // Cyrillic look-alikes, zero-width spaces, fullwidth letters and spaced out letters
+        let normalized = normalize("Frее сrурtо\u{200B}\u{200B}! ｆｒｅｅ   m o n e y f.r.e.e");
+        assert_eq!(normalized.skeleton, "free crypto! free money free");
+        assert!(normalized.obfuscation.is_heavy());
+        assert_eq!(normalized.obfuscation.techniques().len(), 3);
+
+        // Plain text in any script is left alone
+        let normalized = normalize("Café au lait, Привет  друзья, e.g. t.me");
+        assert_eq!(
+            normalized.skeleton,
+            "cafe au lait, привет друзья, e.g. t.me"
+        );
+
+        // Russian words made up of letters that look Latin are still Russian
+        let normalized = normalize("Все в сборе, скоро начнём. Рок-концерт у Тома");
+        assert_eq!(
+            normalized.skeleton,
+            "все в сборе, скоро начнём. рок-концерт у тома"
+        );
+        assert_eq!(normalized.obfuscation, Obfuscation::default());
+
+        // Other scripts keep their marks and joiners
+        for text in [
+            "नमस्ते दोस्तों, आज की बैठक कब है?",
+            "สวัสดีครับ ทุกคน พรุ่งนี้ประชุมกี่โมง",
+            "مَرْحَبًا بِالجَمِيع، كيف حالكم؟",
+            "می\u{200C}خواهم بیایم",
+        ] {
+            let normalized = normalize(text);
+            assert_eq!(normalized.skeleton, text);
+            assert_eq!(normalized.obfuscation, Obfuscation::default(), "{}", text);
+        }
+
+        // Emoji, numbers and lists are no disguise
+        for text in [
+            "Agenda: 1\u{FE0F}\u{20E3} intro 2\u{FE0F}\u{20E3} demo 3\u{FE0F}\u{20E3}",
+            "count down 3 2 1 go",
+            "Plan: A B C D",
+            "Great job 👍🏽❤\u{FE0F}",
+        ] {
+            assert_eq!(
+                normalize(text).obfuscation,
+                Obfuscation::default(),
+                "{}",
+                text
+            );
+        }
+        assert_eq!(skeleton("count down 3 2 1 go"), "count down 3 2 1 go");
+    }
+}
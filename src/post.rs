@@ -1,20 +1,49 @@
-use crate::detect::SpamCheckResult;
+use crate::config::{Settings, ShadowMode};
+use crate::detect::{MsgType, SpamCheckResult, message_text};
+use crate::sender::Sender;
 use crate::state::AppState;
 use std::sync::Arc;
 use teloxide::prelude::*;
 use teloxide::types::{
     ChatPermissions, InlineKeyboardButton, InlineKeyboardMarkup, Message, MessageId,
+    ReplyParameters,
 };
+use teloxide::utils::html;
 use tracing::info;
 
-pub async fn process_spam(
-    bot: &Bot,
-    message: &Message,
-    res: SpamCheckResult,
-    state: Arc<AppState>,
-) {
-    let user = message.from.as_ref();
-    let user_display = match user {
+/// What to do with a classified message
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Leave the message alone
+    Ignore,
+    /// Report the message to admins without acting on it
+    Flag,
+    /// Delete the message and restrict the sender
+    Delete,
+}
+
+/// Decide the action for a verdict according to the per-category thresholds
+pub fn decide(res: &SpamCheckResult, settings: &Settings) -> Action {
+    if res.msg_type == MsgType::NotSpam {
+        return Action::Ignore;
+    }
+
+    let threshold = settings.threshold(res.msg_type);
+    if res.confidence >= threshold.delete {
+        Action::Delete
+    } else if res.confidence >= threshold.flag {
+        Action::Flag
+    } else {
+        Action::Ignore
+    }
+}
+
+fn user_display(message: &Message) -> String {
+    if let Some(c) = message.sender_chat.as_ref() {
+        return format!("{} ({})", c.title().unwrap_or("Channel"), c.id);
+    }
+
+    match message.from.as_ref() {
         Some(u) => {
             let name = format!("{} {}", u.first_name, u.last_name.as_deref().unwrap_or(""))
                 .trim()
@@ -22,15 +51,136 @@ pub async fn process_spam(
             format!("{} ({})", name, u.id)
         }
         None => "Unknown".to_string(),
-    };
+    }
+}
 
-    let chat = &message.chat;
-    let message_text = message
-        .text()
+/// Stop a sender from posting for 24 hours. Channels can't be restricted, only banned until an
+/// admin dismisses the report.
+async fn mute_sender(bot: &Bot, chat_id: ChatId, sender: Sender) {
+    match sender {
+        Sender::User(user_id) => {
+            let until_date = chrono::Utc::now() + chrono::Duration::days(1);
+
+            if let Err(e) = bot
+                .restrict_chat_member(chat_id, user_id, ChatPermissions::empty())
+                .until_date(until_date)
+                .await
+            {
+                tracing::error!("Failed to restrict user {}: {}", user_id, e);
+            } else {
+                info!("User {} restricted until {}", user_id, until_date);
+            }
+        }
+        Sender::Chat(sender_chat_id) => {
+            if let Err(e) = bot.ban_chat_sender_chat(chat_id, sender_chat_id).await {
+                tracing::error!("Failed to ban sender chat {}: {}", sender_chat_id, e);
+            } else {
+                info!("Sender chat {} banned", sender_chat_id);
+            }
+        }
+    }
+}
+
+fn mute_note(sender: Sender) -> &'static str {
+    match sender {
+        Sender::User(_) => "User has been banned for 24 hours.",
+        Sender::Chat(_) => "Channel has been banned until dismissed.",
+    }
+}
+
+/// First 50 characters of the text or caption of a message
+fn preview(message: &Message) -> String {
+    message_text(message)
         .unwrap_or("<no text>")
         .chars()
         .take(50)
-        .collect::<String>();
+        .collect()
+}
+
+/// Log the original version of an edited message and describe it for notifications
+fn edit_note(message: &Message, original: Option<&Message>) -> String {
+    if message.edit_date().is_none() {
+        return String::new();
+    }
+
+    match original {
+        Some(original) => {
+            info!(
+                "Message {} in chat {} was edited, original text: {:?}",
+                message.id,
+                message.chat.id,
+                message_text(original)
+            );
+            format!(
+                "\nEdited from (first 50 chars): <tg-spoiler>{}</tg-spoiler>",
+                html::escape(&preview(original))
+            )
+        }
+        None => "\nEdited message, original version unknown".to_string(),
+    }
+}
+
+/// Reply to a suspicious message asking admins to review it, without deleting anything
+pub async fn flag_spam(
+    bot: &Bot,
+    message: &Message,
+    original: Option<&Message>,
+    res: &SpamCheckResult,
+    state: &AppState,
+) {
+    let Some(sender) = Sender::of(message) else {
+        return;
+    };
+    let chat = &message.chat;
+
+    info!(
+        "Chat: {} ({}) | User: {} | Flagged: {:?} ({:.2})",
+        chat.title().unwrap_or(""),
+        chat.id,
+        user_display(message),
+        res.msg_type,
+        res.confidence,
+    );
+
+    let keyboard = InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("Ignore (TU Only)", format!("ignore:{}", sender)),
+        InlineKeyboardButton::callback("Kick (Admin Only)", format!("kick:{}", sender)),
+    ]]);
+
+    let notification_text = format!(
+        "Possible spam, admins please review.\n\nType: {:?}\nConfidence: {:.2}\nReason: {}\nUser: {}{}",
+        res.msg_type,
+        res.confidence,
+        html::escape(&res.reason),
+        html::escape(&user_display(message)),
+        edit_note(message, original),
+    );
+
+    match bot
+        .send_message(chat.id, notification_text)
+        .parse_mode(teloxide::types::ParseMode::Html)
+        .reply_parameters(ReplyParameters::new(message.id))
+        .reply_markup(keyboard)
+        .await
+    {
+        Ok(sent_msg) => state.track_spam_notification(chat.id, sender, sent_msg.id.0),
+        Err(e) => tracing::error!("Failed to send spam report: {}", e),
+    }
+}
+
+pub async fn process_spam(
+    bot: &Bot,
+    message: &Message,
+    original: Option<&Message>,
+    res: SpamCheckResult,
+    state: Arc<AppState>,
+) {
+    let sender = Sender::of(message);
+    let user_display = user_display(message);
+
+    let chat = &message.chat;
+    let message_text = preview(message);
+    let edit_note = edit_note(message, original);
 
     info!(
         "Chat: {} ({}) | User: {} | Type: {:?}",
@@ -47,9 +197,9 @@ pub async fn process_spam(
         info!("Deleted spam message from {}", user_display);
     }
 
-    if let Some(user) = user {
-        // Check if there's an existing notification for this user and delete it
-        if let Some(existing_msg_id) = state.get_spam_notification(chat.id, user.id)
+    if let Some(sender) = sender {
+        // Check if there's an existing notification for this sender and delete it
+        if let Some(existing_msg_id) = state.get_spam_notification(chat.id, sender)
             && let Err(e) = bot
                 .delete_message(chat.id, MessageId(existing_msg_id))
                 .await
@@ -57,27 +207,22 @@ pub async fn process_spam(
             tracing::error!("Failed to delete old spam notification: {}", e);
         }
 
-        // Ban user for 24 hours
-        let until_date = chrono::Utc::now() + chrono::Duration::days(1);
-
-        if let Err(e) = bot
-            .restrict_chat_member(chat.id, user.id, ChatPermissions::empty())
-            .until_date(until_date)
-            .await
-        {
-            tracing::error!("Failed to restrict user {}: {}", user.id, e);
-        } else {
-            info!("User {} restricted until {}", user.id, until_date);
-        }
+        mute_sender(bot, chat.id, sender).await;
 
         let keyboard = InlineKeyboardMarkup::new(vec![vec![
-            InlineKeyboardButton::callback("Dismiss (TU Only)", format!("dismiss:{}", user.id)),
-            InlineKeyboardButton::callback("Kick (Admin Only)", format!("kick:{}", user.id)),
+            InlineKeyboardButton::callback("Dismiss (TU Only)", format!("dismiss:{}", sender)),
+            InlineKeyboardButton::callback("Kick (Admin Only)", format!("kick:{}", sender)),
         ]]);
 
         let notification_text = format!(
-            "Spam detected!\n\nType: {:?}\nUser: {}\nMessage (first 50 chars): <tg-spoiler>{}</tg-spoiler>\n\nUser has been banned for 24 hours.",
-            res.msg_type, user_display, message_text
+            "Spam detected!\n\nType: {:?}\nConfidence: {:.2}\nReason: {}\nUser: {}\nMessage (first 50 chars): <tg-spoiler>{}</tg-spoiler>{}\n\n{}",
+            res.msg_type,
+            res.confidence,
+            html::escape(&res.reason),
+            html::escape(&user_display),
+            html::escape(&message_text),
+            edit_note,
+            mute_note(sender)
         );
 
         match bot
@@ -88,7 +233,7 @@ pub async fn process_spam(
         {
             Ok(sent_msg) => {
                 // Track this notification
-                state.track_spam_notification(chat.id, user.id, sent_msg.id.0);
+                state.track_spam_notification(chat.id, sender, sent_msg.id.0);
             }
             Err(e) => {
                 tracing::error!("Failed to send spam notification: {}", e);
@@ -96,3 +241,149 @@ pub async fn process_spam(
         }
     }
 }
+
+/// Mute the sender of a message that couldn't be classified and ask for a human review
+pub async fn hold_message(bot: &Bot, message: &Message, state: &AppState) {
+    let Some(sender) = Sender::of(message) else {
+        return;
+    };
+    let chat = &message.chat;
+
+    info!(
+        "Chat: {} ({}) | User: {} | Held for review",
+        chat.title().unwrap_or(""),
+        chat.id,
+        user_display(message),
+    );
+
+    // Mute until reviewed, but never longer than a regular spam ban
+    mute_sender(bot, chat.id, sender).await;
+
+    let keyboard = InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("Dismiss (TU Only)", format!("dismiss:{}", sender)),
+        InlineKeyboardButton::callback("Kick (Admin Only)", format!("kick:{}", sender)),
+    ]]);
+
+    let notification_text = format!(
+        "Spam check is unavailable, message held for review.\n\nUser: {}\n\n{}",
+        user_display(message),
+        mute_note(sender),
+    );
+
+    match bot
+        .send_message(chat.id, notification_text)
+        .reply_parameters(ReplyParameters::new(message.id))
+        .reply_markup(keyboard)
+        .await
+    {
+        Ok(sent_msg) => state.track_spam_notification(chat.id, sender, sent_msg.id.0),
+        Err(e) => tracing::error!("Failed to send hold notification: {}", e),
+    }
+}
+
+/// Log what would have been done with a message in shadow mode, and in `report` mode also tell
+/// the admin chat. Reports never go to the chat itself, its members shouldn't see them.
+pub async fn shadow_report(
+    bot: &Bot,
+    settings: &Settings,
+    mode: ShadowMode,
+    message: &Message,
+    outcome: &str,
+    res: Option<&SpamCheckResult>,
+) {
+    let chat = &message.chat;
+    info!(
+        "Shadow | Chat: {} ({}) | User: {} | Would {} | Verdict: {:?}",
+        chat.title().unwrap_or(""),
+        chat.id,
+        user_display(message),
+        outcome,
+        res.map(|r| (r.msg_type, r.confidence)),
+    );
+
+    let Some(admin_chat_id) = settings.admin_chat_id else {
+        return;
+    };
+    if mode != ShadowMode::Report {
+        return;
+    }
+
+    let verdict = match res {
+        Some(res) => format!(
+            "\nType: {:?}\nConfidence: {:.2}\nReason: {}",
+            res.msg_type,
+            res.confidence,
+            html::escape(&res.reason)
+        ),
+        None => String::new(),
+    };
+    let link = message
+        .url()
+        .map(|url| format!("\nLink: {}", url))
+        .unwrap_or_default();
+    let text = format!(
+        "Shadow mode in {} ({}): would {}.{}\nUser: {}\nMessage (first 50 chars): <tg-spoiler>{}</tg-spoiler>{}",
+        html::escape(chat.title().unwrap_or("")),
+        chat.id,
+        outcome,
+        verdict,
+        html::escape(&user_display(message)),
+        html::escape(&preview(message)),
+        link,
+    );
+
+    if let Err(e) = bot
+        .send_message(ChatId(admin_chat_id), text)
+        .parse_mode(teloxide::types::ParseMode::Html)
+        .await
+    {
+        tracing::error!("Failed to send shadow report: {}", e);
+    }
+}
+
+/// Send a notification to the admin chat, or to the given chat if none is configured
+pub async fn notify_admins(bot: &Bot, settings: &Settings, chat_id: ChatId, text: String) {
+    let target = settings.admin_chat_id.map(ChatId).unwrap_or(chat_id);
+    if let Err(e) = bot.send_message(target, text).await {
+        tracing::error!("Failed to notify admins: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn test_decide_thresholds() {
+        let mut settings = test_support::settings(Default::default());
+        settings.thresholds = serde_json::from_value(serde_json::json!({
+            "scam": {"delete": 0.9, "flag": 0.5},
+            "unsolicited_promotion": {"flag": 0.5},
+        }))
+        .unwrap();
+        let decide = |msg_type, confidence| {
+            decide(
+                &SpamCheckResult {
+                    confidence,
+                    ..SpamCheckResult::certain(msg_type, "")
+                },
+                &settings,
+            )
+        };
+
+        assert_eq!(decide(MsgType::Scam, 0.9), Action::Delete);
+        assert_eq!(decide(MsgType::Scam, 0.89), Action::Flag);
+        assert_eq!(decide(MsgType::Scam, 0.5), Action::Flag);
+        assert_eq!(decide(MsgType::Scam, 0.49), Action::Ignore);
+        // Only flagging configured, deleting takes certainty
+        assert_eq!(decide(MsgType::UnsolicitedPromotion, 0.99), Action::Flag);
+        assert_eq!(decide(MsgType::UnsolicitedPromotion, 1.0), Action::Delete);
+        // No thresholds, always acted upon
+        assert_eq!(decide(MsgType::Phishing, 0.0), Action::Delete);
+        assert_eq!(decide(MsgType::NotSpam, 1.0), Action::Ignore);
+
+        settings.thresholds.get_mut(&MsgType::Scam).unwrap().flag = 0.95;
+        assert!(settings.validate().is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use crate::detect::MsgType;
+use crate::links::normalize_domain;
+use crate::normalize;
+use anyhow::Context;
+use regex::Regex;
+use serde::Deserialize;
+use std::sync::LazyLock;
+use tokio::fs;
+
+static INVITE_LINK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b(?:t\.me|telegram\.(?:me|dog))/(?:\+|joinchat/)[\w-]+").unwrap()
+});
+
+static CRYPTO_WALLET: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(concat!(
+        r"\b(?:",
+        r"bc1[a-z0-9]{25,59}",               // BTC bech32
+        r"|[13][a-km-zA-HJ-NP-Z1-9]{25,34}", // BTC legacy
+        r"|0x[a-fA-F0-9]{40}",               // ETH / EVM
+        r"|T[1-9A-HJ-NP-Za-km-z]{33}",       // TRON
+        r")\b"
+    ))
+    .unwrap()
+});
+
+/// On-disk format of the rules file
+///
+/// ```toml
+/// banned_domains = ["evil.example"]
+/// invite_links = "unsolicited_promotion"
+/// crypto_wallets = "scam"
+///
+/// [[rules]]
+/// keywords = ["join my vip channel"]
+/// verdict = "unsolicited_promotion"
+///
+/// [[rules]]
+/// regex = "^!report$"
+/// verdict = "not_spam"
+/// ```
+#[derive(Debug, Deserialize, Default)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+    /// Messages linking to these domains (or their subdomains) are classified as phishing, like
+    /// with `denied_domains`
+    #[serde(default)]
+    banned_domains: Vec<String>,
+    /// Verdict for messages containing Telegram invite links
+    invite_links: Option<MsgType>,
+    /// Verdict for messages containing crypto wallet addresses
+    crypto_wallets: Option<MsgType>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RuleConfig {
+    regex: Option<String>,
+    #[serde(default)]
+    keywords: Vec<String>,
+    verdict: MsgType,
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Regex(Regex),
+    /// Skeletons of the keywords, any of which triggers the rule
+    Keywords(Vec<String>),
+}
+
+#[derive(Debug)]
+struct Rule {
+    matcher: Matcher,
+    verdict: MsgType,
+}
+
+/// Deterministic pre-filter run before the classifier
+#[derive(Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    banned_domains: Vec<String>,
+    invite_links: Option<MsgType>,
+    crypto_wallets: Option<MsgType>,
+}
+
+impl Rules {
+    pub async fn load_from_file(path: &str) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path).await?;
+        Self::parse(&content)
+    }
+
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        let file: RulesFile = toml::from_str(content)?;
+
+        let rules = file
+            .rules
+            .into_iter()
+            .map(|r| {
+                let matcher = match r.regex {
+                    Some(re) => Matcher::Regex(Regex::new(&re)?),
+                    None if !r.keywords.is_empty() => Matcher::Keywords(
+                        r.keywords.iter().map(|k| normalize::skeleton(k)).collect(),
+                    ),
+                    None => anyhow::bail!("rule needs either `regex` or `keywords`"),
+                };
+                Ok(Rule {
+                    matcher,
+                    verdict: r.verdict,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            rules,
+            banned_domains: file
+                .banned_domains
+                .iter()
+                .map(|d| {
+                    normalize_domain(d).with_context(|| {
+                        format!("invalid banned domain {:?}, give it without a path", d)
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?,
+            invite_links: file.invite_links,
+            crypto_wallets: file.crypto_wallets,
+        })
+    }
+
+    /// Banned domains, checked together with the domain denylists against the extracted links
+    pub fn banned_domains(&self) -> &[String] {
+        &self.banned_domains
+    }
+
+    /// Return a verdict if the text is decided by the rules, `None` if the classifier should be consulted.
+    /// Patterns are matched against both the text and its skeleton, so that look-alike letters
+    /// and invisible characters don't get around them.
+    pub fn check(&self, text: &str) -> Option<MsgType> {
+        let skeleton = normalize::skeleton(text);
+        let texts = [text, skeleton.as_str()];
+
+        // Explicit rules are evaluated in order; the first match wins
+        for rule in &self.rules {
+            let matched = match &rule.matcher {
+                Matcher::Regex(re) => texts.iter().any(|t| re.is_match(t)),
+                Matcher::Keywords(keywords) => keywords.iter().any(|k| skeleton.contains(k)),
+            };
+            if matched {
+                return Some(rule.verdict);
+            }
+        }
+
+        if let Some(verdict) = self.invite_links
+            && texts.iter().any(|t| INVITE_LINK.is_match(t))
+        {
+            return Some(verdict);
+        }
+
+        // Addresses are case sensitive, the skeleton would mangle them
+        if let Some(verdict) = self.crypto_wallets
+            && CRYPTO_WALLET.is_match(text)
+        {
+            return Some(verdict);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_verdicts() {
+        let rules = Rules::parse(
+            r#"
+            banned_domains = ["evil.example"]
+            invite_links = "unsolicited_promotion"
+            crypto_wallets = "scam"
+
+            [[rules]]
+            regex = "^!report$"
+            verdict = "not_spam"
+
+            [[rules]]
+            keywords = ["Join My VIP"]
+            verdict = "unsolicited_promotion"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(rules.check("!report"), Some(MsgType::NotSpam));
+        assert_eq!(
+            rules.check("join my vip group now"),
+            Some(MsgType::UnsolicitedPromotion)
+        );
+        assert_eq!(
+            rules.check("Jоin mу\u{200B} V I P"),
+            Some(MsgType::UnsolicitedPromotion)
+        );
+        assert_eq!(rules.banned_domains(), ["evil.example"]);
+        assert_eq!(rules.check("login at https://secure.evil.example/x"), None);
+        assert_eq!(
+            rules.check("come to t.me/+AbCdEf123"),
+            Some(MsgType::UnsolicitedPromotion)
+        );
+        assert_eq!(
+            rules.check("send to 0x52908400098527886E0F7030069857D2E4169EE7"),
+            Some(MsgType::Scam)
+        );
+        assert_eq!(rules.check("hello everyone"), None);
+    }
+}
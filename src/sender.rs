@@ -0,0 +1,58 @@
+use std::fmt;
+use std::str::FromStr;
+use teloxide::types::{ChatId, Message, UserId};
+
+/// Identity a message is attributed to for counting, classification and punishment
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Sender {
+    User(UserId),
+    /// A channel (or another group) the message was posted on behalf of
+    Chat(ChatId),
+}
+
+impl Sender {
+    /// Messages posted as a channel carry a placeholder bot in `from`, so `sender_chat` wins
+    pub fn of(message: &Message) -> Option<Self> {
+        message
+            .sender_chat
+            .as_ref()
+            .map(|c| Self::Chat(c.id))
+            .or_else(|| message.from.as_ref().map(|u| Self::User(u.id)))
+    }
+
+    /// Label used for the sender in classifier prompts
+    pub fn label(&self) -> String {
+        match self {
+            Self::User(id) => format!("User{}", id),
+            Self::Chat(id) => format!("Channel{}", id),
+        }
+    }
+}
+
+impl From<UserId> for Sender {
+    fn from(id: UserId) -> Self {
+        Self::User(id)
+    }
+}
+
+/// Formats as the raw ID. Chat IDs are negative, so they never collide with user IDs.
+impl fmt::Display for Sender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User(id) => write!(f, "{}", id),
+            Self::Chat(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+impl FromStr for Sender {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('-') {
+            Ok(Self::Chat(ChatId(s.parse()?)))
+        } else {
+            Ok(Self::User(UserId(s.parse()?)))
+        }
+    }
+}
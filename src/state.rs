@@ -1,18 +1,134 @@
+use crate::bayes::NaiveBayes;
+use crate::budget::{BudgetExhausted, BudgetUsage};
+use crate::config::{BudgetSettings, FailPolicy, ShadowMode};
+use crate::detect::{MsgType, SenderProfile, Usage};
+use crate::embedding::IndexedMessage;
+use crate::examples::{self, LabeledExample};
+use crate::fingerprint;
+use crate::post::Action;
+use crate::sender::Sender;
+use crate::usage::{self, DailyUsage};
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
-use teloxide::types::{ChatId, Message, UserId};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Mutex, RwLock};
+use teloxide::types::{ChatId, Message, MessageId};
 use tokio::fs;
 
-#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct AppState {
     pub counters: DashMap<String, u64>,
     #[serde(default)]
     pub message_history: DashMap<i64, VecDeque<Message>>,
+    /// First version of recent messages of unchecked senders, to show edits against
+    #[serde(default)]
+    pub originals: DashMap<i64, VecDeque<Message>>,
+    #[serde(skip)]
+    pub spam_notifications: DashMap<String, i32>, // Key: "chat_id:sender", Value: MessageId
+    #[serde(default)]
+    pub chat_configs: DashMap<i64, ChatConfig>,
+    #[serde(default)]
+    pub recheck_queue: DashMap<i64, VecDeque<Message>>,
     #[serde(skip)]
-    pub spam_notifications: DashMap<String, i32>, // Key: "chat_id:user_id", Value: MessageId
+    pub classifier_failures: DashMap<i64, u64>,
+    /// Classifier usage counted against the budgets, key: chat ID or "global"
+    #[serde(default)]
+    pub budget_usage: DashMap<String, BudgetUsage>,
+    /// Makes checking and spending the budgets a single step
+    #[serde(skip)]
+    budget_lock: Mutex<()>,
+    /// Classifier usage per chat and day (YYYY-MM-DD, UTC)
+    #[serde(default)]
+    pub token_usage: DashMap<i64, BTreeMap<String, DailyUsage>>,
+    /// Unix timestamp of when a member joined a chat, key: "sender:chat_id"
+    #[serde(default)]
+    pub join_dates: DashMap<String, i64>,
+    /// What the bot would have done in chats in shadow mode
+    #[serde(default)]
+    pub shadow_stats: DashMap<i64, ShadowStats>,
+    /// Reported messages awaiting a Kick, Dismiss or Ignore, key: "sender:chat_id" like the
+    /// notification they belong to
+    #[serde(default)]
+    pub pending_labels: DashMap<String, PendingLabel>,
+    /// Verdicts confirmed or overturned by humans, oldest first
+    #[serde(default)]
+    pub labeled_examples: DashMap<i64, VecDeque<LabeledExample>>,
+    /// Recent messages of trusted users, the ham the local model learns from
+    #[serde(default)]
+    pub trusted_ham: DashMap<i64, VecDeque<String>>,
+    #[serde(default)]
+    pub local_model: RwLock<NaiveBayes>,
+    /// Embedded messages labeled by the moderators of each chat, for semantic search
+    #[serde(default)]
+    pub semantic_index: DashMap<i64, VecDeque<IndexedMessage>>,
+    /// SimHash fingerprints of confirmed spam per chat, with its category and when it was last
+    /// seen. Chats are kept apart since their policies differ.
+    #[serde(default)]
+    pub spam_fingerprints: DashMap<i64, HashMap<u64, (MsgType, i64)>>,
+}
+
+/// Verdicts reached in shadow mode since it was last switched on
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct ShadowStats {
+    pub checked: u64,
+    pub would_delete: u64,
+    pub would_flag: u64,
+    /// Messages the fail policy would have held or deleted
+    pub would_fail_closed: u64,
 }
 
+/// Text and verdict of a reported message, labeled once a human acts on the report
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PendingLabel {
+    pub text: String,
+    pub msg_type: MsgType,
+    /// Unix timestamp of the report
+    pub reported_at: i64,
+}
+
+/// Per-chat settings changed by admins through bot commands
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ChatConfig {
+    /// Overrides the global fail policy
+    #[serde(default)]
+    pub fail_policy: Option<FailPolicy>,
+    /// Overrides the global shadow mode
+    #[serde(default)]
+    pub shadow_mode: Option<ShadowMode>,
+    /// Chat specific moderation rules appended to the classifier system prompt
+    #[serde(default)]
+    pub policy: Option<String>,
+    /// Added to the global domain allowlist
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    /// Added to the global domain denylist
+    #[serde(default)]
+    pub denied_domains: Vec<String>,
+}
+
+/// Maximum number of messages waiting for a recheck in a single chat
+const MAX_RECHECK_QUEUE: usize = 100;
+
+/// Maximum number of original messages kept per chat to show edits against
+const MAX_ORIGINALS: usize = 500;
+
+/// Maximum number of labeled examples kept per chat
+const MAX_LABELED_EXAMPLES: usize = 200;
+
+/// Maximum number of reports awaiting a label
+const MAX_PENDING_LABELS: usize = 1000;
+
+/// Reports left alone for longer are no longer labeled
+const PENDING_LABEL_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Maximum number of spam fingerprints kept per chat
+const MAX_SPAM_FINGERPRINTS: usize = 1000;
+
+/// Maximum number of messages of trusted users kept per chat
+const MAX_TRUSTED_HAM: usize = 200;
+
+const GLOBAL_BUDGET_KEY: &str = "global";
+
 impl AppState {
     pub fn new() -> Self {
         Self::default()
@@ -33,33 +149,230 @@ impl AppState {
         Ok(())
     }
 
-    pub fn key(chat_id: ChatId, user_id: UserId) -> String {
-        format!("{}:{}", user_id, chat_id)
+    pub fn key(chat_id: ChatId, sender: impl Into<Sender>) -> String {
+        format!("{}:{}", sender.into(), chat_id)
     }
 
-    #[allow(dead_code)]
-    pub fn get_count(&self, chat_id: ChatId, user_id: UserId) -> u64 {
-        let key = Self::key(chat_id, user_id);
+    pub fn get_count(&self, chat_id: ChatId, sender: impl Into<Sender>) -> u64 {
+        let key = Self::key(chat_id, sender);
         self.counters.get(&key).map(|v| *v.value()).unwrap_or(0)
     }
 
     /// Increment and return the updated count
-    pub fn increment(&self, chat_id: ChatId, user_id: UserId) -> u64 {
-        let key = Self::key(chat_id, user_id);
+    pub fn increment(&self, chat_id: ChatId, sender: impl Into<Sender>) -> u64 {
+        let key = Self::key(chat_id, sender);
         let mut entry = self.counters.entry(key).or_insert(0);
         *entry += 1;
         *entry
     }
 
-    /// Reset the counter for a specific user in a chat
-    pub fn reset(&self, chat_id: ChatId, user_id: UserId) {
-        let key = Self::key(chat_id, user_id);
+    /// Reset the counter for a specific sender in a chat
+    pub fn reset(&self, chat_id: ChatId, sender: impl Into<Sender>) {
+        let key = Self::key(chat_id, sender);
         self.counters.remove(&key);
     }
 
-    /// Check if a user is trusted (message count >= threshold)
-    pub fn is_trusted_user(&self, chat_id: ChatId, user_id: UserId, threshold: u64) -> bool {
-        self.get_count(chat_id, user_id) >= threshold
+    /// Check if a sender is trusted (message count >= threshold)
+    pub fn is_trusted_user(
+        &self,
+        chat_id: ChatId,
+        sender: impl Into<Sender>,
+        threshold: u64,
+    ) -> bool {
+        self.get_count(chat_id, sender) >= threshold
+    }
+
+    /// Count what would have been done with a message in shadow mode
+    pub fn record_shadow(&self, chat_id: ChatId, action: &Action) {
+        let mut stats = self.shadow_stats.entry(chat_id.0).or_default();
+        stats.checked += 1;
+        match action {
+            Action::Delete => stats.would_delete += 1,
+            Action::Flag => stats.would_flag += 1,
+            Action::Ignore => {}
+        }
+    }
+
+    /// Count a message the fail policy would have held or deleted in shadow mode
+    pub fn record_shadow_failure(&self, chat_id: ChatId) {
+        let mut stats = self.shadow_stats.entry(chat_id.0).or_default();
+        stats.checked += 1;
+        stats.would_fail_closed += 1;
+    }
+
+    /// Remember the text and verdict of a message reported to the chat, until a human labels it
+    pub fn await_label(&self, chat_id: ChatId, sender: Sender, text: &str, msg_type: MsgType) {
+        let now = chrono::Utc::now().timestamp();
+        self.pending_labels
+            .retain(|_, label| now - label.reported_at < PENDING_LABEL_TTL_SECS);
+        let key = Self::key(chat_id, sender);
+        if self.pending_labels.len() >= MAX_PENDING_LABELS
+            && !self.pending_labels.contains_key(&key)
+            && let Some(oldest) = self
+                .pending_labels
+                .iter()
+                .min_by_key(|e| e.value().reported_at)
+                .map(|e| e.key().clone())
+        {
+            self.pending_labels.remove(&oldest);
+        }
+        self.pending_labels.insert(
+            key,
+            PendingLabel {
+                text: text.to_string(),
+                msg_type,
+                reported_at: now,
+            },
+        );
+    }
+
+    /// Store the last reported message of a sender as an example, confirmed as spam by a kick
+    /// or overturned by a dismissal or an ignored report
+    pub fn label_pending(
+        &self,
+        chat_id: ChatId,
+        sender: Sender,
+        spam: bool,
+    ) -> Option<LabeledExample> {
+        let (_, pending) = self.pending_labels.remove(&Self::key(chat_id, sender))?;
+        let now = chrono::Utc::now().timestamp();
+        if now - pending.reported_at >= PENDING_LABEL_TTL_SECS {
+            return None;
+        }
+        let example = LabeledExample {
+            text: pending.text,
+            label: if spam {
+                pending.msg_type
+            } else {
+                MsgType::NotSpam
+            },
+            labeled_at: now,
+        };
+        let mut examples = self.labeled_examples.entry(chat_id.0).or_default();
+        examples.push_back(example.clone());
+        if examples.len() > MAX_LABELED_EXAMPLES {
+            examples.pop_front();
+        }
+        Some(example)
+    }
+
+    /// Labeled examples of a chat to show the classifier
+    pub fn few_shot_examples(&self, chat_id: ChatId, count: usize) -> Vec<LabeledExample> {
+        self.labeled_examples
+            .get(&chat_id.0)
+            .map(|examples| examples::select(&examples, count))
+            .unwrap_or_default()
+    }
+
+    /// Remember confirmed spam of a chat to recognize copies of it there
+    pub fn add_spam_fingerprint(&self, chat_id: ChatId, text: &str, msg_type: MsgType) {
+        let Some(hash) = fingerprint::simhash(text) else {
+            return;
+        };
+        let mut fingerprints = self.spam_fingerprints.entry(chat_id.0).or_default();
+        if fingerprints.len() >= MAX_SPAM_FINGERPRINTS
+            && !fingerprints.contains_key(&hash)
+            && let Some(oldest) = fingerprints
+                .iter()
+                .min_by_key(|(_, (_, seen))| *seen)
+                .map(|(hash, _)| *hash)
+        {
+            fingerprints.remove(&oldest);
+        }
+        fingerprints.insert(hash, (msg_type, chrono::Utc::now().timestamp()));
+    }
+
+    /// Forget known spam of a chat similar to a text found to be fine there
+    pub fn remove_spam_fingerprints(&self, chat_id: ChatId, text: &str, min_similarity: f64) {
+        if let Some(hash) = fingerprint::simhash(text)
+            && let Some(mut fingerprints) = self.spam_fingerprints.get_mut(&chat_id.0)
+        {
+            fingerprints.retain(|known, _| fingerprint::similarity(hash, *known) < min_similarity);
+        }
+    }
+
+    /// Category of and similarity to the known spam of a chat most similar to a text, if at
+    /// least `min_similarity`. The matched spam counts as seen again.
+    pub fn find_near_duplicate(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        min_similarity: f64,
+    ) -> Option<(MsgType, f64)> {
+        let hash = fingerprint::simhash(text)?;
+        let mut fingerprints = self.spam_fingerprints.get_mut(&chat_id.0)?;
+        let (matched, similarity) = fingerprints
+            .keys()
+            .map(|known| (*known, fingerprint::similarity(hash, *known)))
+            .filter(|(_, similarity)| *similarity >= min_similarity)
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        let (msg_type, seen) = fingerprints.get_mut(&matched)?;
+        *seen = chrono::Utc::now().timestamp();
+        Some((*msg_type, similarity))
+    }
+
+    /// Keep a message of a trusted user to train the local model on
+    pub fn record_trusted_ham(&self, chat_id: ChatId, text: &str) {
+        let mut messages = self.trusted_ham.entry(chat_id.0).or_default();
+        messages.push_back(text.to_string());
+        if messages.len() > MAX_TRUSTED_HAM {
+            messages.pop_front();
+        }
+    }
+
+    /// Train the local model again on the labeled examples and trusted messages of all chats.
+    /// Until both classes have `min_samples` samples, the model stays unused.
+    pub fn retrain_local_model(&self, min_samples: u64) -> (u64, u64) {
+        let examples = self
+            .labeled_examples
+            .iter()
+            .flat_map(|e| e.value().clone())
+            .collect::<Vec<_>>();
+        let ham = self
+            .trusted_ham
+            .iter()
+            .flat_map(|e| e.value().clone())
+            .collect::<Vec<_>>();
+        let model = NaiveBayes::train(
+            examples
+                .iter()
+                .map(|e| (e.text.as_str(), e.label != MsgType::NotSpam))
+                .chain(ham.iter().map(|text| (text.as_str(), false))),
+        );
+
+        let (spam, ham) = model.samples();
+        let model = if spam >= min_samples && ham >= min_samples {
+            model
+        } else {
+            NaiveBayes::default()
+        };
+        *self.local_model.write().unwrap() = model;
+        (spam, ham)
+    }
+
+    /// Spam probability of a text according to the local model, if trained
+    pub fn local_spam_probability(&self, text: &str) -> Option<f64> {
+        self.local_model.read().unwrap().spam_probability(text)
+    }
+
+    /// Remember when a member joined a chat
+    pub fn record_join(&self, chat_id: ChatId, sender: impl Into<Sender>, date: i64) {
+        self.join_dates.insert(Self::key(chat_id, sender), date);
+    }
+
+    pub fn record_leave(&self, chat_id: ChatId, sender: impl Into<Sender>) {
+        self.join_dates.remove(&Self::key(chat_id, sender));
+    }
+
+    /// Profile of a sender for the classifier, at the time of `now` (unix timestamp)
+    pub fn sender_profile(&self, chat_id: ChatId, sender: Sender, now: i64) -> SenderProfile {
+        SenderProfile {
+            message_count: self.get_count(chat_id, sender),
+            member_for: self
+                .join_dates
+                .get(&Self::key(chat_id, sender))
+                .map(|joined| chrono::Duration::seconds(now - *joined)),
+        }
     }
 
     /// Add a message to the chat's history, maintaining a maximum size
@@ -75,6 +388,37 @@ impl AppState {
         }
     }
 
+    /// Keep the first version of a message, so that later edits can be compared to it
+    pub fn record_original(&self, chat_id: ChatId, message: &Message) {
+        let mut originals = self.originals.entry(chat_id.0).or_default();
+        if originals.iter().any(|m| m.id == message.id) {
+            return;
+        }
+        originals.push_back(message.clone());
+        if originals.len() > MAX_ORIGINALS {
+            originals.pop_front();
+        }
+    }
+
+    /// The first version of a message seen by the bot
+    pub fn find_original(&self, chat_id: ChatId, message_id: MessageId) -> Option<Message> {
+        self.originals
+            .get(&chat_id.0)
+            .and_then(|queue| queue.iter().find(|m| m.id == message_id).cloned())
+    }
+
+    /// Replace an edited message in the chat's history, adding it if it isn't there anymore
+    pub fn replace_message(&self, chat_id: ChatId, message: Message, max_size: usize) {
+        if let Some(mut queue) = self.message_history.get_mut(&chat_id.0)
+            && let Some(old) = queue.iter_mut().find(|m| m.id == message.id)
+        {
+            *old = message;
+            return;
+        }
+
+        self.add_message(chat_id, message, max_size);
+    }
+
     /// Clear message context for a specific chat_id
     pub fn clear_context(&self, chat_id: ChatId) {
         let chat_key = chat_id.0;
@@ -92,28 +436,190 @@ impl AppState {
             .unwrap_or_default()
     }
 
-    /// Track or update a spam notification for a user
-    pub fn track_spam_notification(&self, chat_id: ChatId, user_id: UserId, message_id: i32) {
-        let key = Self::key(chat_id, user_id);
+    /// Track or update a spam notification for a sender
+    pub fn track_spam_notification(
+        &self,
+        chat_id: ChatId,
+        sender: impl Into<Sender>,
+        message_id: i32,
+    ) {
+        let key = Self::key(chat_id, sender);
         self.spam_notifications.insert(key, message_id);
     }
 
     /// Get existing spam notification message ID
-    pub fn get_spam_notification(&self, chat_id: ChatId, user_id: UserId) -> Option<i32> {
-        let key = Self::key(chat_id, user_id);
+    pub fn get_spam_notification(&self, chat_id: ChatId, sender: impl Into<Sender>) -> Option<i32> {
+        let key = Self::key(chat_id, sender);
         self.spam_notifications.get(&key).map(|v| *v.value())
     }
 
     /// Remove spam notification tracking (called when dismissed/kicked)
-    pub fn remove_spam_notification(&self, chat_id: ChatId, user_id: UserId) {
-        let key = Self::key(chat_id, user_id);
+    pub fn remove_spam_notification(&self, chat_id: ChatId, sender: impl Into<Sender>) {
+        let key = Self::key(chat_id, sender);
         self.spam_notifications.remove(&key);
     }
+
+    /// Get the per-chat settings
+    pub fn chat_config(&self, chat_id: ChatId) -> ChatConfig {
+        self.chat_configs
+            .get(&chat_id.0)
+            .map(|c| c.value().clone())
+            .unwrap_or_default()
+    }
+
+    /// Modify the per-chat settings
+    pub fn update_chat_config(&self, chat_id: ChatId, f: impl FnOnce(&mut ChatConfig)) {
+        f(&mut self.chat_configs.entry(chat_id.0).or_default());
+    }
+
+    /// Queue a message to be classified again later, dropping the oldest one if the queue is full
+    pub fn queue_recheck(&self, chat_id: ChatId, message: Message) {
+        let mut entry = self.recheck_queue.entry(chat_id.0).or_default();
+        entry.push_back(message);
+
+        while entry.len() > MAX_RECHECK_QUEUE {
+            if let Some(dropped) = entry.pop_front() {
+                tracing::warn!(
+                    "Recheck queue of chat {} is full, dropping message {}",
+                    chat_id,
+                    dropped.id
+                );
+            }
+        }
+    }
+
+    /// Take all queued messages of every chat, leaving the queues empty
+    pub fn take_recheck_queue(&self) -> Vec<Message> {
+        self.recheck_queue
+            .iter_mut()
+            .flat_map(|mut q| q.drain(..).collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// Record a classifier failure and return the number of consecutive failures
+    pub fn record_classifier_failure(&self, chat_id: ChatId) -> u64 {
+        let mut entry = self.classifier_failures.entry(chat_id.0).or_insert(0);
+        *entry += 1;
+        *entry
+    }
+
+    /// Record a classifier success and return the number of failures it ended
+    pub fn record_classifier_success(&self, chat_id: ChatId) -> u64 {
+        self.classifier_failures
+            .remove(&chat_id.0)
+            .map(|(_, v)| v)
+            .unwrap_or(0)
+    }
+
+    /// Count a classifier request against the chat and global budgets, unless one of them is
+    /// exhausted
+    pub fn try_spend_request(
+        &self,
+        chat_id: ChatId,
+        budget: &BudgetSettings,
+    ) -> Result<(), BudgetExhausted> {
+        let _guard = self.budget_lock.lock().unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let chat_key = chat_id.to_string();
+        for (key, limits) in [
+            (chat_key.as_str(), &budget.per_chat),
+            (GLOBAL_BUDGET_KEY, &budget.global),
+        ] {
+            let mut usage = self.budget_usage.entry(key.to_string()).or_default();
+            if let Some(limit) = usage.exhausted(limits, now) {
+                return Err(BudgetExhausted {
+                    scope: if key == GLOBAL_BUDGET_KEY {
+                        "all chats".to_string()
+                    } else {
+                        format!("chat {}", chat_id)
+                    },
+                    limit,
+                });
+            }
+        }
+
+        for key in [chat_key.as_str(), GLOBAL_BUDGET_KEY] {
+            self.budget_usage
+                .entry(key.to_string())
+                .or_default()
+                .spend_request(now);
+        }
+        Ok(())
+    }
+
+    /// Count the tokens of a classifier response against the chat and global budgets
+    pub fn spend_tokens(&self, chat_id: ChatId, tokens: u64) {
+        let now = chrono::Utc::now().timestamp();
+        for key in [chat_id.to_string().as_str(), GLOBAL_BUDGET_KEY] {
+            self.budget_usage
+                .entry(key.to_string())
+                .or_default()
+                .spend_tokens(tokens, now);
+        }
+    }
+
+    /// Record the usage of a classifier request, dropping days older than the retention period
+    pub fn record_usage(&self, chat_id: ChatId, usage: Usage, retention_days: i64) {
+        let today = chrono::Utc::now().date_naive();
+        let oldest = (today - chrono::Duration::days(retention_days)).to_string();
+
+        let mut days = self.token_usage.entry(chat_id.0).or_default();
+        days.entry(today.to_string()).or_default().add(usage);
+        days.retain(|day, _| *day > oldest);
+    }
+
+    /// Usage of a chat per day over the last `days` days, oldest first
+    pub fn usage_report(&self, chat_id: ChatId, days: i64) -> Vec<(String, DailyUsage)> {
+        let since = (chrono::Utc::now().date_naive() - chrono::Duration::days(days)).to_string();
+        self.token_usage
+            .get(&chat_id.0)
+            .map(|usage| {
+                usage
+                    .iter()
+                    .filter(|(day, _)| **day > since)
+                    .map(|(day, u)| (day.clone(), *u))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Export the usage of one chat, or of all chats, as CSV
+    pub fn export_usage(&self, chat_id: Option<ChatId>) -> String {
+        let mut chats = self
+            .token_usage
+            .iter()
+            .filter(|e| chat_id.is_none_or(|id| id.0 == *e.key()))
+            .map(|e| (*e.key(), e.value().clone()))
+            .collect::<Vec<_>>();
+        chats.sort_by_key(|(id, _)| *id);
+
+        usage::to_csv(chats.iter().flat_map(|(id, days)| {
+            days.iter()
+                .map(move |(day, usage)| (*id, day.as_str(), usage))
+        }))
+    }
+
+    /// Usage of the chat and global budgets, for `/stats`
+    pub fn describe_budget(&self, chat_id: ChatId, budget: &BudgetSettings) -> String {
+        let now = chrono::Utc::now().timestamp();
+        let usage = |key: &str| {
+            self.budget_usage
+                .get(key)
+                .map(|u| *u.value())
+                .unwrap_or_default()
+        };
+        format!(
+            "Budget of this chat: {}\nBudget of all chats: {}",
+            usage(&chat_id.to_string()).describe(&budget.per_chat, now),
+            usage(GLOBAL_BUDGET_KEY).describe(&budget.global, now),
+        )
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::text_message;
     use teloxide::types::{ChatId, UserId};
 
     #[test]
@@ -127,4 +633,73 @@ mod tests {
         assert_eq!(state.get_count(cid, uid), 1);
         assert_eq!(state.increment(cid, uid), 2);
     }
+
+    #[test]
+    fn test_originals_keep_first_version() {
+        let state = AppState::new();
+        let cid = ChatId(-100);
+        state.record_original(cid, &text_message(1, 1, "hello"));
+        state.record_original(cid, &text_message(1, 1, "Free crypto"));
+        for id in 2..=MAX_ORIGINALS as i32 {
+            state.record_original(cid, &text_message(id, 1, "filler"));
+        }
+        state.add_message(cid, text_message(1, 1, "Free crypto"), 1);
+        state.add_message(cid, text_message(2, 1, "filler"), 1);
+
+        let original = state.find_original(cid, MessageId(1)).unwrap();
+        assert_eq!(original.text(), Some("hello"));
+
+        state.record_original(cid, &text_message(0, 1, "newer"));
+        assert!(state.find_original(cid, MessageId(1)).is_none());
+    }
+
+    #[test]
+    fn test_pending_labels_expire_and_are_bounded() {
+        let state = AppState::new();
+        let cid = ChatId(-100);
+        state.await_label(cid, UserId(1).into(), "Free crypto", MsgType::Scam);
+        state
+            .pending_labels
+            .get_mut(&AppState::key(cid, UserId(1)))
+            .unwrap()
+            .reported_at -= PENDING_LABEL_TTL_SECS;
+        assert!(state.label_pending(cid, UserId(1).into(), true).is_none());
+
+        for id in 0..=MAX_PENDING_LABELS as u64 {
+            state.await_label(cid, UserId(id).into(), "Free crypto", MsgType::Scam);
+        }
+        assert_eq!(state.pending_labels.len(), MAX_PENDING_LABELS);
+
+        let last = UserId(MAX_PENDING_LABELS as u64).into();
+        let example = state.label_pending(cid, last, false).unwrap();
+        assert_eq!(example.label, MsgType::NotSpam);
+        assert_eq!(state.few_shot_examples(cid, 6).len(), 1);
+    }
+
+    #[test]
+    fn test_usage_export() {
+        let state = AppState::new();
+        let usage = Usage {
+            prompt_tokens: 100,
+            completion_tokens: 20,
+        };
+        state.record_usage(ChatId(1), usage, 90);
+        state.record_usage(ChatId(1), usage, 90);
+        state.record_usage(ChatId(-2), usage, 90);
+
+        let report = state.usage_report(ChatId(1), 7);
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].1.requests, 2);
+        assert_eq!(report[0].1.prompt_tokens, 200);
+
+        let today = chrono::Utc::now().date_naive();
+        assert_eq!(
+            state.export_usage(Some(ChatId(-2))),
+            format!(
+                "chat_id,date,requests,prompt_tokens,completion_tokens\n-2,{},1,100,20\n",
+                today
+            )
+        );
+        assert_eq!(state.export_usage(None).lines().count(), 3);
+    }
 }
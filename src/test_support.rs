@@ -0,0 +1,242 @@
+//! Local HTTP server speaking the Gemini `generateContent` or the OpenAI chat completions wire
+//! format, for tests
+use crate::config::{Backend, ClassifierSettings, Settings};
+use crate::detect::MsgType;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use teloxide::types::Message;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Scripted reply of the mock server
+#[derive(Debug, Clone)]
+pub enum Reply {
+    /// A well-formed verdict
+    Verdict(MsgType, f32),
+    /// Arbitrary model output, e.g. malformed JSON
+    Text(String),
+    /// An error status code with an empty body
+    Status(u16),
+    /// Wait before sending the reply
+    Delayed(Duration, Box<Reply>),
+}
+
+/// Wire format of the mock server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Wire {
+    Gemini,
+    ChatCompletions,
+}
+
+struct Script {
+    wire: Wire,
+    replies: Vec<Reply>,
+    /// Bodies of the requests received so far
+    requests: Vec<serde_json::Value>,
+}
+
+pub struct MockGemini {
+    pub base_url: String,
+    wire: Wire,
+    script: Arc<Mutex<Script>>,
+    server: JoinHandle<()>,
+}
+
+impl MockGemini {
+    /// Start a Gemini server answering with `replies` in order, repeating the last one
+    pub async fn start(replies: Vec<Reply>) -> Self {
+        Self::start_with(Wire::Gemini, replies).await
+    }
+
+    /// Start an OpenAI-compatible chat completions server answering with `replies` in order,
+    /// repeating the last one
+    pub async fn start_chat_completions(replies: Vec<Reply>) -> Self {
+        Self::start_with(Wire::ChatCompletions, replies).await
+    }
+
+    async fn start_with(wire: Wire, replies: Vec<Reply>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let base_url = match wire {
+            Wire::Gemini => format!("http://{}/v1beta/", addr),
+            Wire::ChatCompletions => format!("http://{}/v1", addr),
+        };
+        let script = Arc::new(Mutex::new(Script {
+            wire,
+            replies,
+            requests: Vec::new(),
+        }));
+
+        let server = tokio::spawn({
+            let script = script.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(stream, script.clone()));
+                }
+            }
+        });
+
+        Self {
+            base_url,
+            wire,
+            script,
+            server,
+        }
+    }
+
+    /// Classifier settings pointing the matching backend at this server
+    pub fn settings(&self) -> ClassifierSettings {
+        match self.wire {
+            Wire::Gemini => ClassifierSettings {
+                base_url: Some(self.base_url.clone()),
+                api_key: Some("test".to_string()),
+                ..Default::default()
+            },
+            Wire::ChatCompletions => ClassifierSettings {
+                backend: Backend::OpenAi,
+                base_url: Some(self.base_url.clone()),
+                api_key: Some("test".to_string()),
+                model: Some("test-model".to_string()),
+            },
+        }
+    }
+
+    /// Bodies of the requests received so far
+    pub fn requests(&self) -> Vec<serde_json::Value> {
+        self.script.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for MockGemini {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// Handle a single request. Every response closes the connection, so no keep-alive handling is
+/// needed.
+async fn serve(mut stream: TcpStream, script: Arc<Mutex<Script>>) {
+    let mut buf = Vec::new();
+    let header_end = loop {
+        let mut chunk = [0; 4096];
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let headers = String::from_utf8_lossy(&buf[..header_end]).to_lowercase();
+    let content_length = headers
+        .lines()
+        .find_map(|l| l.strip_prefix("content-length:"))
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while buf.len() < header_end + content_length {
+        let mut chunk = [0; 4096];
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+
+    let (wire, reply) = {
+        let mut script = script.lock().unwrap();
+        let body = serde_json::from_slice(&buf[header_end..]).unwrap_or_default();
+        script.requests.push(body);
+        let index = (script.requests.len() - 1).min(script.replies.len().saturating_sub(1));
+        let reply = script
+            .replies
+            .get(index)
+            .cloned()
+            .unwrap_or(Reply::Status(500));
+        (script.wire, reply)
+    };
+
+    let mut reply = reply;
+    while let Reply::Delayed(delay, inner) = reply {
+        tokio::time::sleep(delay).await;
+        reply = *inner;
+    }
+
+    let (status, body) = match reply {
+        Reply::Verdict(msg_type, confidence) => (
+            200,
+            generation_response(
+                wire,
+                &json!({
+                    "msg_type": msg_type,
+                    "confidence": confidence,
+                    "reason": "Scripted verdict",
+                })
+                .to_string(),
+            ),
+        ),
+        Reply::Text(text) => (200, generation_response(wire, &text)),
+        Reply::Status(status) => (status, String::new()),
+        Reply::Delayed(..) => unreachable!(),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+fn generation_response(wire: Wire, text: &str) -> String {
+    match wire {
+        Wire::Gemini => json!({
+            "candidates": [{
+                "content": {"parts": [{"text": text}], "role": "model"},
+                "finishReason": "STOP",
+            }],
+            "usageMetadata": {
+                "promptTokenCount": 100,
+                "candidatesTokenCount": 10,
+                "totalTokenCount": 110,
+            },
+        }),
+        Wire::ChatCompletions => json!({
+            "id": "chatcmpl-test",
+            "object": "chat.completion",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": text},
+                "finish_reason": "stop",
+            }],
+            "usage": {
+                "prompt_tokens": 100,
+                "completion_tokens": 10,
+                "total_tokens": 110,
+            },
+        }),
+    }
+    .to_string()
+}
+
+/// Settings with defaults for everything but the classifier
+pub fn settings(classifier: ClassifierSettings) -> Settings {
+    let mut settings: Settings = serde_json::from_value(json!({})).unwrap();
+    settings.classifier = classifier;
+    settings
+}
+
+/// A plain text group message from `user_id`
+pub fn text_message(id: i32, user_id: u64, text: &str) -> Message {
+    serde_json::from_value(json!({
+        "message_id": id,
+        "date": 0,
+        "chat": {"id": -100, "type": "supergroup", "title": "Test"},
+        "from": {"id": user_id, "is_bot": false, "first_name": "Test"},
+        "text": text,
+    }))
+    .unwrap()
+}
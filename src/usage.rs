@@ -0,0 +1,38 @@
+use crate::detect::Usage;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+/// Classifier requests and tokens of a chat on one day
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DailyUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl DailyUsage {
+    pub fn add(&mut self, usage: Usage) {
+        self.requests += 1;
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+    }
+
+    pub fn merge(&mut self, other: &DailyUsage) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+/// Format usage rows of (chat ID, day, usage) as CSV
+pub fn to_csv<'a>(rows: impl IntoIterator<Item = (i64, &'a str, &'a DailyUsage)>) -> String {
+    let mut csv = "chat_id,date,requests,prompt_tokens,completion_tokens\n".to_string();
+    for (chat_id, date, usage) in rows {
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{}",
+            chat_id, date, usage.requests, usage.prompt_tokens, usage.completion_tokens
+        );
+    }
+    csv
+}